@@ -0,0 +1,556 @@
+use crate::checksum::PseudoHeader;
+use crate::{
+    EthernetFrame, IcmpPacket, Icmpv6Packet, Ipv4Packet, Ipv6Packet, TcpSegment, UdpDatagram,
+};
+use pnet::packet::ethernet::{EtherType, EtherTypes, MutableEthernetPacket};
+use pnet::packet::icmp::{self, IcmpPacket as pnet_IcmpPacket};
+use pnet::packet::icmpv6::{self, Icmpv6Packet as pnet_Icmpv6Packet};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{self, Ipv4Flags, MutableIpv4Packet};
+use pnet::packet::ipv6::MutableIpv6Packet;
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpPacket};
+use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::packet::{ethernet, ipv6, Packet};
+use pnet::util::MacAddr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Most option bytes an IPv4 or TCP header can carry
+const MAX_OPTIONS_LENGTH: usize = 40;
+
+/// Pad header options with zeros to a multiple of 4 bytes
+///
+/// Panics if the options don't fit in a header
+fn pad_options(options: &[u8]) -> Vec<u8> {
+    assert!(
+        options.len() <= MAX_OPTIONS_LENGTH,
+        "Options are {} bytes long, more than the {MAX_OPTIONS_LENGTH} a header can carry",
+        options.len()
+    );
+    let mut padded = options.to_vec();
+    padded.resize(options.len().next_multiple_of(4), 0);
+    padded
+}
+
+/// Builds an ethernet frame
+#[derive(Clone, Debug)]
+pub struct EthernetBuilder {
+    source: MacAddr,
+    destination: MacAddr,
+    ethertype: EtherType,
+    payload: Vec<u8>,
+}
+
+impl EthernetBuilder {
+    /// Create an EthernetBuilder for an empty IPv4 frame
+    pub fn new(source: MacAddr, destination: MacAddr) -> EthernetBuilder {
+        EthernetBuilder {
+            source,
+            destination,
+            ethertype: EtherTypes::Ipv4,
+            payload: vec![],
+        }
+    }
+
+    /// Carry raw bytes of another protocol
+    pub fn payload(mut self, ethertype: EtherType, payload: &[u8]) -> EthernetBuilder {
+        self.ethertype = ethertype;
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Carry an IPv4 packet
+    pub fn ipv4(self, packet: Ipv4Builder) -> EthernetBuilder {
+        self.payload(EtherTypes::Ipv4, packet.build().packet())
+    }
+
+    /// Carry an IPv6 packet
+    pub fn ipv6(self, packet: Ipv6Builder) -> EthernetBuilder {
+        self.payload(EtherTypes::Ipv6, packet.build().packet())
+    }
+
+    pub fn build(&self) -> EthernetFrame<'static> {
+        let mut buffer =
+            vec![0u8; MutableEthernetPacket::minimum_packet_size() + self.payload.len()];
+        let mut frame = MutableEthernetPacket::new(&mut buffer).unwrap();
+        frame.set_source(self.source);
+        frame.set_destination(self.destination);
+        frame.set_ethertype(self.ethertype);
+        frame.set_payload(&self.payload);
+        EthernetFrame::from(ethernet::EthernetPacket::owned(buffer).unwrap())
+    }
+}
+
+/// Builds an IPv4 packet, filling in the IHL, total length and header checksum
+#[derive(Clone, Debug)]
+pub struct Ipv4Builder {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    tos: u8,
+    identification: u16,
+    dont_fragment: bool,
+    more_fragments: bool,
+    fragment_offset: u16,
+    ttl: u8,
+    options: Vec<u8>,
+    protocol: IpNextHeaderProtocol,
+    payload: Vec<u8>,
+}
+
+impl Ipv4Builder {
+    /// Create an Ipv4Builder for an empty packet with a TTL of 64
+    pub fn new(source: Ipv4Addr, destination: Ipv4Addr) -> Ipv4Builder {
+        Ipv4Builder {
+            source,
+            destination,
+            tos: 0,
+            identification: 0,
+            dont_fragment: false,
+            more_fragments: false,
+            fragment_offset: 0,
+            ttl: 64,
+            options: vec![],
+            protocol: IpNextHeaderProtocols::Reserved,
+            payload: vec![],
+        }
+    }
+
+    /// Set the type of service byte, holding both the DSCP and ECN fields
+    pub fn tos(mut self, tos: u8) -> Ipv4Builder {
+        self.tos = tos;
+        self
+    }
+
+    pub fn identification(mut self, identification: u16) -> Ipv4Builder {
+        self.identification = identification;
+        self
+    }
+
+    pub fn dont_fragment(mut self, dont_fragment: bool) -> Ipv4Builder {
+        self.dont_fragment = dont_fragment;
+        self
+    }
+
+    pub fn more_fragments(mut self, more_fragments: bool) -> Ipv4Builder {
+        self.more_fragments = more_fragments;
+        self
+    }
+
+    /// Set where the payload sits in the original datagram, in bytes
+    ///
+    /// Panics if the offset isn't a multiple of 8
+    pub fn fragment_offset(mut self, offset: u16) -> Ipv4Builder {
+        assert!(
+            offset % 8 == 0,
+            "Fragment offset {offset} isn't a multiple of 8"
+        );
+        self.fragment_offset = offset / 8;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u8) -> Ipv4Builder {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set the raw options, padded with zeros to a multiple of 4 bytes
+    ///
+    /// Panics if the options are longer than 40 bytes
+    pub fn options(mut self, options: &[u8]) -> Ipv4Builder {
+        self.options = pad_options(options);
+        self
+    }
+
+    /// Carry raw bytes of another protocol
+    pub fn payload(mut self, protocol: IpNextHeaderProtocol, payload: &[u8]) -> Ipv4Builder {
+        self.protocol = protocol;
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Carry a TCP segment, with its checksum computed for the addresses of the packet
+    pub fn tcp(self, segment: TcpBuilder) -> Ipv4Builder {
+        let segment = segment.build_ipv4(self.source, self.destination);
+        self.payload(IpNextHeaderProtocols::Tcp, segment.packet())
+    }
+
+    /// Carry a UDP datagram, with its checksum computed for the addresses of the packet
+    pub fn udp(self, datagram: UdpBuilder) -> Ipv4Builder {
+        let datagram = datagram.build_ipv4(self.source, self.destination);
+        self.payload(IpNextHeaderProtocols::Udp, datagram.packet())
+    }
+
+    /// Carry an ICMP message
+    pub fn icmp(self, message: IcmpBuilder) -> Ipv4Builder {
+        self.payload(IpNextHeaderProtocols::Icmp, message.build().packet())
+    }
+
+    /// Panics if the packet is longer than 65535 bytes
+    pub fn build(&self) -> Ipv4Packet<'static> {
+        let header_length = MutableIpv4Packet::minimum_packet_size() + self.options.len();
+        let total_length = u16::try_from(header_length + self.payload.len())
+            .expect("IPv4 packet longer than 65535 bytes");
+        let mut buffer = vec![0u8; total_length as usize];
+        buffer[MutableIpv4Packet::minimum_packet_size()..header_length]
+            .copy_from_slice(&self.options);
+        let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        packet.set_version(4);
+        packet.set_header_length((header_length / 4) as u8);
+        packet.set_dscp(self.tos >> 2);
+        packet.set_ecn(self.tos & 0b11);
+        packet.set_total_length(total_length);
+        packet.set_identification(self.identification);
+        let mut flags = 0;
+        if self.dont_fragment {
+            flags |= Ipv4Flags::DontFragment;
+        }
+        if self.more_fragments {
+            flags |= Ipv4Flags::MoreFragments;
+        }
+        packet.set_flags(flags);
+        packet.set_fragment_offset(self.fragment_offset);
+        packet.set_ttl(self.ttl);
+        packet.set_next_level_protocol(self.protocol);
+        packet.set_source(self.source);
+        packet.set_destination(self.destination);
+        packet.set_payload(&self.payload);
+        packet.set_checksum(ipv4::checksum(&packet.to_immutable()));
+        Ipv4Packet::from(ipv4::Ipv4Packet::owned(buffer).unwrap())
+    }
+}
+
+/// Builds an IPv6 packet, filling in the payload length and next header
+#[derive(Clone, Debug)]
+pub struct Ipv6Builder {
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+    traffic_class: u8,
+    flow_label: u32,
+    hop_limit: u8,
+    next_header: IpNextHeaderProtocol,
+    payload: Vec<u8>,
+}
+
+impl Ipv6Builder {
+    /// Create an Ipv6Builder for an empty packet with a hop limit of 64
+    pub fn new(source: Ipv6Addr, destination: Ipv6Addr) -> Ipv6Builder {
+        Ipv6Builder {
+            source,
+            destination,
+            traffic_class: 0,
+            flow_label: 0,
+            hop_limit: 64,
+            next_header: IpNextHeaderProtocols::Ipv6NoNxt,
+            payload: vec![],
+        }
+    }
+
+    pub fn traffic_class(mut self, traffic_class: u8) -> Ipv6Builder {
+        self.traffic_class = traffic_class;
+        self
+    }
+
+    /// Set the flow label, keeping its low 20 bits
+    pub fn flow_label(mut self, flow_label: u32) -> Ipv6Builder {
+        self.flow_label = flow_label & 0xf_ffff;
+        self
+    }
+
+    pub fn hop_limit(mut self, hop_limit: u8) -> Ipv6Builder {
+        self.hop_limit = hop_limit;
+        self
+    }
+
+    /// Carry raw bytes of another protocol, or of extension headers followed by one
+    pub fn payload(mut self, next_header: IpNextHeaderProtocol, payload: &[u8]) -> Ipv6Builder {
+        self.next_header = next_header;
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Carry a TCP segment, with its checksum computed for the addresses of the packet
+    pub fn tcp(self, segment: TcpBuilder) -> Ipv6Builder {
+        let segment = segment.build_ipv6(self.source, self.destination);
+        self.payload(IpNextHeaderProtocols::Tcp, segment.packet())
+    }
+
+    /// Carry a UDP datagram, with its checksum computed for the addresses of the packet
+    pub fn udp(self, datagram: UdpBuilder) -> Ipv6Builder {
+        let datagram = datagram.build_ipv6(self.source, self.destination);
+        self.payload(IpNextHeaderProtocols::Udp, datagram.packet())
+    }
+
+    /// Carry an ICMPv6 message, with its checksum computed for the addresses of the packet
+    pub fn icmpv6(self, message: IcmpBuilder) -> Ipv6Builder {
+        let message = message.build_v6(self.source, self.destination);
+        self.payload(IpNextHeaderProtocols::Icmpv6, message.packet())
+    }
+
+    /// Panics if the payload is longer than 65535 bytes
+    pub fn build(&self) -> Ipv6Packet<'static> {
+        let payload_length =
+            u16::try_from(self.payload.len()).expect("IPv6 payload longer than 65535 bytes");
+        let mut buffer = vec![0u8; MutableIpv6Packet::minimum_packet_size() + self.payload.len()];
+        let mut packet = MutableIpv6Packet::new(&mut buffer).unwrap();
+        packet.set_version(6);
+        packet.set_traffic_class(self.traffic_class);
+        packet.set_flow_label(self.flow_label);
+        packet.set_payload_length(payload_length);
+        packet.set_next_header(self.next_header);
+        packet.set_hop_limit(self.hop_limit);
+        packet.set_source(self.source);
+        packet.set_destination(self.destination);
+        packet.set_payload(&self.payload);
+        Ipv6Packet::from(ipv6::Ipv6Packet::owned(buffer).unwrap())
+    }
+}
+
+/// Builds a TCP segment, filling in the data offset and the checksum over the IP pseudo-header
+#[derive(Clone, Debug)]
+pub struct TcpBuilder {
+    source_port: u16,
+    destination_port: u16,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u8,
+    window: u16,
+    urgent_pointer: u16,
+    options: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl TcpBuilder {
+    /// Create a TcpBuilder for an empty segment without flags and with a window of 65535
+    pub fn new(source_port: u16, destination_port: u16) -> TcpBuilder {
+        TcpBuilder {
+            source_port,
+            destination_port,
+            sequence: 0,
+            acknowledgement: 0,
+            flags: 0,
+            window: u16::MAX,
+            urgent_pointer: 0,
+            options: vec![],
+            payload: vec![],
+        }
+    }
+
+    pub fn sequence(mut self, sequence: u32) -> TcpBuilder {
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn acknowledgement(mut self, acknowledgement: u32) -> TcpBuilder {
+        self.acknowledgement = acknowledgement;
+        self
+    }
+
+    /// Set the flags, as a combination of pnet's `TcpFlags`
+    pub fn flags(mut self, flags: u8) -> TcpBuilder {
+        self.flags = flags;
+        self
+    }
+
+    pub fn window(mut self, window: u16) -> TcpBuilder {
+        self.window = window;
+        self
+    }
+
+    pub fn urgent_pointer(mut self, urgent_pointer: u16) -> TcpBuilder {
+        self.urgent_pointer = urgent_pointer;
+        self
+    }
+
+    /// Set the raw options, padded with zeros to a multiple of 4 bytes
+    ///
+    /// Panics if the options are longer than 40 bytes
+    pub fn options(mut self, options: &[u8]) -> TcpBuilder {
+        self.options = pad_options(options);
+        self
+    }
+
+    pub fn payload(mut self, payload: &[u8]) -> TcpBuilder {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Build the segment, with its checksum computed for IPv4 addresses
+    pub fn build_ipv4(&self, source: Ipv4Addr, destination: Ipv4Addr) -> TcpSegment<'static> {
+        self.build(PseudoHeader::V4(source, destination))
+    }
+
+    /// Build the segment, with its checksum computed for IPv6 addresses
+    pub fn build_ipv6(&self, source: Ipv6Addr, destination: Ipv6Addr) -> TcpSegment<'static> {
+        self.build(PseudoHeader::V6(source, destination))
+    }
+
+    fn build(&self, pseudo_header: PseudoHeader) -> TcpSegment<'static> {
+        let header_length = MutableTcpPacket::minimum_packet_size() + self.options.len();
+        let mut buffer = vec![0u8; header_length + self.payload.len()];
+        buffer[MutableTcpPacket::minimum_packet_size()..header_length]
+            .copy_from_slice(&self.options);
+        let mut segment = MutableTcpPacket::new(&mut buffer).unwrap();
+        segment.set_source(self.source_port);
+        segment.set_destination(self.destination_port);
+        segment.set_sequence(self.sequence);
+        segment.set_acknowledgement(self.acknowledgement);
+        segment.set_data_offset((header_length / 4) as u8);
+        segment.set_flags(self.flags);
+        segment.set_window(self.window);
+        segment.set_urgent_ptr(self.urgent_pointer);
+        segment.set_payload(&self.payload);
+        let checksum = match pseudo_header {
+            PseudoHeader::V4(source, destination) => {
+                tcp::ipv4_checksum(&segment.to_immutable(), &source, &destination)
+            }
+            PseudoHeader::V6(source, destination) => {
+                tcp::ipv6_checksum(&segment.to_immutable(), &source, &destination)
+            }
+        };
+        segment.set_checksum(checksum);
+        TcpSegment::from(TcpPacket::owned(buffer).unwrap())
+    }
+}
+
+/// Builds a UDP datagram, filling in the length and the checksum over the IP pseudo-header
+#[derive(Clone, Debug)]
+pub struct UdpBuilder {
+    source_port: u16,
+    destination_port: u16,
+    payload: Vec<u8>,
+}
+
+impl UdpBuilder {
+    /// Create a UdpBuilder for an empty datagram
+    pub fn new(source_port: u16, destination_port: u16) -> UdpBuilder {
+        UdpBuilder {
+            source_port,
+            destination_port,
+            payload: vec![],
+        }
+    }
+
+    pub fn payload(mut self, payload: &[u8]) -> UdpBuilder {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Build the datagram, with its checksum computed for IPv4 addresses
+    pub fn build_ipv4(&self, source: Ipv4Addr, destination: Ipv4Addr) -> UdpDatagram<'static> {
+        self.build(PseudoHeader::V4(source, destination))
+    }
+
+    /// Build the datagram, with its checksum computed for IPv6 addresses
+    pub fn build_ipv6(&self, source: Ipv6Addr, destination: Ipv6Addr) -> UdpDatagram<'static> {
+        self.build(PseudoHeader::V6(source, destination))
+    }
+
+    /// Panics if the datagram is longer than 65535 bytes
+    fn build(&self, pseudo_header: PseudoHeader) -> UdpDatagram<'static> {
+        let length = u16::try_from(MutableUdpPacket::minimum_packet_size() + self.payload.len())
+            .expect("UDP datagram longer than 65535 bytes");
+        let mut buffer = vec![0u8; length as usize];
+        let mut datagram = MutableUdpPacket::new(&mut buffer).unwrap();
+        datagram.set_source(self.source_port);
+        datagram.set_destination(self.destination_port);
+        datagram.set_length(length);
+        datagram.set_payload(&self.payload);
+        let checksum = match pseudo_header {
+            PseudoHeader::V4(source, destination) => {
+                udp::ipv4_checksum(&datagram.to_immutable(), &source, &destination)
+            }
+            PseudoHeader::V6(source, destination) => {
+                udp::ipv6_checksum(&datagram.to_immutable(), &source, &destination)
+            }
+        };
+        // A checksum of zero means none was computed, so it is sent as all ones instead
+        datagram.set_checksum(if checksum == 0 { 0xffff } else { checksum });
+        UdpDatagram::from(UdpPacket::owned(buffer).unwrap())
+    }
+}
+
+/// Builds an ICMP or ICMPv6 message, filling in the checksum
+#[derive(Clone, Debug)]
+pub struct IcmpBuilder {
+    icmp_type: u8,
+    code: u8,
+    rest_of_header: [u8; 4],
+    payload: Vec<u8>,
+}
+
+impl IcmpBuilder {
+    /// Create an IcmpBuilder for an empty message
+    pub fn new(icmp_type: u8, code: u8) -> IcmpBuilder {
+        IcmpBuilder {
+            icmp_type,
+            code,
+            rest_of_header: [0; 4],
+            payload: vec![],
+        }
+    }
+
+    /// Create an IcmpBuilder for an ICMP echo request
+    pub fn echo_request(identifier: u16, sequence: u16) -> IcmpBuilder {
+        IcmpBuilder::new(8, 0).echo(identifier, sequence)
+    }
+
+    /// Create an IcmpBuilder for an ICMPv6 echo request
+    pub fn echo_request_v6(identifier: u16, sequence: u16) -> IcmpBuilder {
+        IcmpBuilder::new(128, 0).echo(identifier, sequence)
+    }
+
+    /// Create an IcmpBuilder for an ICMP echo reply
+    pub fn echo_reply(identifier: u16, sequence: u16) -> IcmpBuilder {
+        IcmpBuilder::new(0, 0).echo(identifier, sequence)
+    }
+
+    /// Create an IcmpBuilder for an ICMPv6 echo reply
+    pub fn echo_reply_v6(identifier: u16, sequence: u16) -> IcmpBuilder {
+        IcmpBuilder::new(129, 0).echo(identifier, sequence)
+    }
+
+    /// Set the four bytes following the checksum, whose meaning depends on the type
+    pub fn rest_of_header(mut self, rest_of_header: [u8; 4]) -> IcmpBuilder {
+        self.rest_of_header = rest_of_header;
+        self
+    }
+
+    /// Set the identifier and sequence number of an echo request or reply
+    pub fn echo(self, identifier: u16, sequence: u16) -> IcmpBuilder {
+        let [id_high, id_low] = identifier.to_be_bytes();
+        let [seq_high, seq_low] = sequence.to_be_bytes();
+        self.rest_of_header([id_high, id_low, seq_high, seq_low])
+    }
+
+    pub fn payload(mut self, payload: &[u8]) -> IcmpBuilder {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Build an ICMP message
+    pub fn build(&self) -> IcmpPacket<'static> {
+        let mut buffer = self.bytes();
+        let checksum = icmp::checksum(&pnet_IcmpPacket::new(&buffer).unwrap());
+        buffer[2..4].copy_from_slice(&checksum.to_be_bytes());
+        IcmpPacket::from(pnet_IcmpPacket::owned(buffer).unwrap())
+    }
+
+    /// Build an ICMPv6 message, with its checksum computed for IPv6 addresses
+    pub fn build_v6(&self, source: Ipv6Addr, destination: Ipv6Addr) -> Icmpv6Packet<'static> {
+        let mut buffer = self.bytes();
+        let checksum = icmpv6::checksum(
+            &pnet_Icmpv6Packet::new(&buffer).unwrap(),
+            &source,
+            &destination,
+        );
+        buffer[2..4].copy_from_slice(&checksum.to_be_bytes());
+        Icmpv6Packet::from(pnet_Icmpv6Packet::owned(buffer).unwrap())
+    }
+
+    /// Message with a zero checksum
+    fn bytes(&self) -> Vec<u8> {
+        let mut buffer = vec![self.icmp_type, self.code, 0, 0];
+        buffer.extend_from_slice(&self.rest_of_header);
+        buffer.extend_from_slice(&self.payload);
+        buffer
+    }
+}
@@ -0,0 +1,427 @@
+use crate::ring_buffer::{RingWindow, TriggerRing};
+use crate::source::PacketReader;
+use crate::spill::SpillFile;
+use crate::statistics::CaptureCounters;
+use crate::{
+    Completed, LayeredPacket, LiveControl, PacketCapture, PacketStore, RotatingWriter, Sharding,
+};
+use rayon::ThreadPool;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often a paused capture of a source that isn't live checks whether it should resume or stop
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many packets can wait in a queue towards a consumer before new ones are dropped
+pub(crate) const QUEUE_DEPTH: usize = 4096;
+
+/// Requests a started PacketCapture sends to its capture thread
+pub(crate) enum CaptureCommand {
+    /// Reply with a copy of the packets stored so far
+    Snapshot(SyncSender<PacketStore>),
+    /// Reply with the packets stored so far, and start storing from scratch
+    Drain(SyncSender<PacketStore>),
+    /// Hand the packets read from now on to another consumer
+    Subscribe(Subscriber),
+}
+
+/// Filter deciding which packets a subscriber receives
+pub(crate) type SubscriberFilter = Box<dyn Fn(&LayeredPacket) -> bool + Send>;
+
+/// Callback a subscriber hands its packets to
+pub(crate) type SubscriberCallback = Box<dyn FnMut(SystemTime, &[u8]) + Send>;
+
+/// Consumer registered on a started capture on top of what the capture does with packets
+pub(crate) struct Subscriber {
+    pub(crate) filter: SubscriberFilter,
+    pub(crate) sink: SubscriberSink,
+}
+
+/// Where a subscriber's packets go
+pub(crate) enum SubscriberSink {
+    Callback(SubscriberCallback),
+    Channel(SyncSender<(SystemTime, Vec<u8>)>),
+}
+
+impl Subscriber {
+    /// Hand a packet over if it passes the filter
+    ///
+    /// Returns false once the subscriber has gone away
+    fn deliver(
+        &mut self,
+        packet: &[u8],
+        timestamp: SystemTime,
+        layered_packet: &LayeredPacket,
+        counters: &CaptureCounters,
+    ) -> bool {
+        if !(self.filter)(layered_packet) {
+            return true;
+        }
+        match &mut self.sink {
+            SubscriberSink::Callback(callback) => {
+                callback(timestamp, packet);
+                true
+            }
+            SubscriberSink::Channel(sender) => {
+                match sender.try_send((timestamp, packet.to_vec())) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        counters.record_buffer_drop();
+                        true
+                    }
+                    Err(TrySendError::Disconnected(_)) => false,
+                }
+            }
+        }
+    }
+}
+
+/// What a capture thread does with the packets it reads
+pub(crate) trait CaptureLoop: Send + 'static {
+    /// Handle a packet read while the capture isn't paused
+    fn packet(
+        &mut self,
+        packet: &[u8],
+        timestamp: SystemTime,
+        counters: &CaptureCounters,
+    ) -> LiveControl;
+
+    /// Handle a read that timed out without a packet
+    fn idle(&mut self, _now: SystemTime) {}
+
+    /// Copy the packets stored so far, sharing rather than copying those spilled to disk
+    fn snapshot(&mut self) -> PacketStore {
+        PacketStore::default()
+    }
+
+    /// Take the packets stored so far, and start storing from scratch
+    fn drain(&mut self) -> PacketStore {
+        PacketStore::default()
+    }
+
+    /// Hand over the packets stored, once the capture stops
+    fn finish(self) -> PacketStore;
+}
+
+/// Everything a capture thread shares with its PacketCapture
+pub(crate) struct CaptureThread {
+    pub(crate) reader: Box<dyn PacketReader>,
+    pub(crate) stop_signal: Arc<AtomicBool>,
+    pub(crate) pause_signal: Arc<AtomicBool>,
+    pub(crate) counters: Arc<CaptureCounters>,
+    pub(crate) commands: Receiver<CaptureCommand>,
+    pub(crate) handoff: SyncSender<PacketStore>,
+}
+
+impl CaptureThread {
+    /// Run a capture loop on a thread of its own
+    ///
+    /// Capture loops block for as long as the capture runs, so they are kept off the rayon pool that decodes results
+    pub(crate) fn spawn(self, capture_loop: impl CaptureLoop) {
+        thread::spawn(move || self.run(capture_loop));
+    }
+
+    fn run(mut self, mut capture_loop: impl CaptureLoop) {
+        let mut subscribers: Vec<Subscriber> = vec![];
+        while !self.stop_signal.load(Ordering::Relaxed) {
+            while let Ok(command) = self.commands.try_recv() {
+                match command {
+                    CaptureCommand::Snapshot(reply) => {
+                        let _ = reply.send(capture_loop.snapshot());
+                    }
+                    CaptureCommand::Drain(reply) => {
+                        let _ = reply.send(capture_loop.drain());
+                    }
+                    CaptureCommand::Subscribe(subscriber) => subscribers.push(subscriber),
+                }
+            }
+            if self.pause_signal.load(Ordering::Relaxed) && !self.reader.is_live() {
+                thread::sleep(PAUSE_POLL_INTERVAL);
+                continue;
+            }
+            match self.reader.next_packet() {
+                Ok(Some(_)) if self.pause_signal.load(Ordering::Relaxed) => continue,
+                Ok(Some((timestamp, packet))) => {
+                    self.counters.record_packet(packet.len());
+                    if !subscribers.is_empty() {
+                        let layered_packet = LayeredPacket::new(packet);
+                        subscribers.retain_mut(|subscriber| {
+                            subscriber.deliver(packet, timestamp, &layered_packet, &self.counters)
+                        });
+                    }
+                    if capture_loop.packet(packet, timestamp, &self.counters) == LiveControl::Stop {
+                        self.stop_signal.store(true, Ordering::Relaxed);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    capture_loop.idle(SystemTime::now())
+                }
+                Err(e) => panic!("Could not read packet: {e}"),
+            }
+        }
+        drop(subscribers);
+        let _ = self.handoff.send(capture_loop.finish());
+    }
+}
+
+/// Stores every packet, moving them to a spill file past the memory limit
+#[derive(Debug)]
+pub(crate) struct StoreLoop {
+    store: PacketStore,
+    spill: Option<SpillFile>,
+    memory_limit: Option<usize>,
+}
+
+impl StoreLoop {
+    pub(crate) fn new(memory_limit: Option<usize>) -> StoreLoop {
+        StoreLoop {
+            store: PacketStore::preallocated(),
+            spill: None,
+            memory_limit,
+        }
+    }
+}
+
+impl StoreLoop {
+    /// Put the spilled packets in front of those still in memory
+    fn hand_over(spill: Option<SpillFile>, store: PacketStore) -> PacketStore {
+        let mut packets = match spill {
+            Some(spill) => spill
+                .into_packets()
+                .unwrap_or_else(|e| panic!("Could not map spilled packets: {e}")),
+            None => PacketStore::default(),
+        };
+        packets.append(store);
+        packets
+    }
+}
+
+impl CaptureLoop for StoreLoop {
+    fn packet(&mut self, packet: &[u8], timestamp: SystemTime, _: &CaptureCounters) -> LiveControl {
+        self.store.push(packet, timestamp);
+        if self
+            .memory_limit
+            .is_some_and(|limit| self.store.bytes_stored() > limit)
+        {
+            let spill = match self.spill.as_mut() {
+                Some(spill) => spill,
+                None => self.spill.insert(
+                    SpillFile::create()
+                        .unwrap_or_else(|e| panic!("Could not create spill file: {e}")),
+                ),
+            };
+            if let Err(e) = spill.spill(&self.store) {
+                panic!("Could not spill packets to disk: {e}");
+            }
+            self.store.clear();
+        }
+        LiveControl::Continue
+    }
+
+    fn snapshot(&mut self) -> PacketStore {
+        let mut snapshot = match self.spill.as_mut() {
+            Some(spill) => spill
+                .packets()
+                .unwrap_or_else(|e| panic!("Could not map spilled packets: {e}")),
+            None => PacketStore::default(),
+        };
+        snapshot.append(self.store.clone());
+        snapshot
+    }
+
+    fn drain(&mut self) -> PacketStore {
+        let store = mem::replace(&mut self.store, PacketStore::preallocated());
+        StoreLoop::hand_over(self.spill.take(), store)
+    }
+
+    fn finish(self) -> PacketStore {
+        StoreLoop::hand_over(self.spill, self.store)
+    }
+}
+
+/// Calls a function on every packet
+pub(crate) struct ProcessLoop<F>(pub(crate) F);
+
+impl<F> CaptureLoop for ProcessLoop<F>
+where
+    F: FnMut(&[u8], SystemTime, &CaptureCounters) -> LiveControl + Send + 'static,
+{
+    fn packet(
+        &mut self,
+        packet: &[u8],
+        timestamp: SystemTime,
+        counters: &CaptureCounters,
+    ) -> LiveControl {
+        (self.0)(packet, timestamp, counters)
+    }
+
+    fn finish(self) -> PacketStore {
+        PacketStore::default()
+    }
+}
+
+/// Spreads packets over the workers of a dedicated rayon pool
+pub(crate) struct PoolLoop {
+    pool: ThreadPool,
+    queues: Vec<SyncSender<Vec<u8>>>,
+    done: Receiver<()>,
+    sharding: Sharding,
+    next_worker: usize,
+}
+
+impl PoolLoop {
+    pub(crate) fn new(
+        workers: usize,
+        sharding: Sharding,
+        callback: impl Fn(Vec<u8>) + Send + Sync + 'static,
+    ) -> PoolLoop {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(workers.max(1))
+            .thread_name(|index| format!("wiretap-worker-{index}"))
+            .build()
+            .unwrap_or_else(|e| panic!("Could not create worker pool: {e}"));
+        let callback = Arc::new(callback);
+        let (done_sender, done) = mpsc::channel::<()>();
+        let queues = (0..workers.max(1))
+            .map(|_| {
+                let (queue, packets) = mpsc::sync_channel::<Vec<u8>>(QUEUE_DEPTH);
+                let callback = Arc::clone(&callback);
+                let done_sender = done_sender.clone();
+                pool.spawn(move || {
+                    let _done_sender = done_sender;
+                    for packet in packets {
+                        callback(packet);
+                    }
+                });
+                queue
+            })
+            .collect();
+        PoolLoop {
+            pool,
+            queues,
+            done,
+            sharding,
+            next_worker: 0,
+        }
+    }
+}
+
+impl CaptureLoop for PoolLoop {
+    fn packet(&mut self, packet: &[u8], _: SystemTime, counters: &CaptureCounters) -> LiveControl {
+        let candidates = match self.sharding {
+            Sharding::RoundRobin => {
+                self.next_worker = (self.next_worker + 1) % self.queues.len();
+                self.queues.len()
+            }
+            Sharding::Flow => {
+                let flow_hash = LayeredPacket::new(packet).flow_hash();
+                self.next_worker = (flow_hash % self.queues.len() as u64) as usize;
+                1
+            }
+        };
+        let mut packet = packet.to_vec();
+        for offset in 0..candidates {
+            match self.queues[(self.next_worker + offset) % self.queues.len()].try_send(packet) {
+                Ok(()) => return LiveControl::Continue,
+                Err(TrySendError::Full(rejected)) | Err(TrySendError::Disconnected(rejected)) => {
+                    packet = rejected
+                }
+            }
+        }
+        counters.record_buffer_drop();
+        LiveControl::Continue
+    }
+
+    fn finish(self) -> PacketStore {
+        drop(self.queues);
+        // Every worker holds a sender, so this returns once they have all emptied their queue
+        let _ = self.done.recv();
+        drop(self.pool);
+        PacketStore::default()
+    }
+}
+
+/// Keeps a window of recent packets and hands over the traffic around each trigger
+pub(crate) struct RingLoop<T, E> {
+    ring: TriggerRing,
+    trigger: T,
+    on_event: E,
+    template: PacketCapture<Completed>,
+}
+
+impl<T, E> RingLoop<T, E>
+where
+    E: FnMut(PacketCapture<Completed>),
+{
+    pub(crate) fn new(
+        before: RingWindow,
+        after: RingWindow,
+        trigger: T,
+        on_event: E,
+        template: PacketCapture<Completed>,
+    ) -> RingLoop<T, E> {
+        RingLoop {
+            ring: TriggerRing::new(before, after),
+            trigger,
+            on_event,
+            template,
+        }
+    }
+
+    fn hand_over(&mut self, store: Option<PacketStore>) {
+        if let Some(store) = store {
+            (self.on_event)(PacketCapture {
+                results: Arc::new(store),
+                ..self.template.transition()
+            });
+        }
+    }
+}
+
+impl<T, E> CaptureLoop for RingLoop<T, E>
+where
+    T: Fn(&LayeredPacket) -> bool + Send + 'static,
+    E: FnMut(PacketCapture<Completed>) + Send + 'static,
+{
+    fn packet(&mut self, packet: &[u8], timestamp: SystemTime, _: &CaptureCounters) -> LiveControl {
+        let trigger = &self.trigger;
+        let event = self
+            .ring
+            .push(timestamp, packet, || trigger(&LayeredPacket::new(packet)));
+        self.hand_over(event);
+        LiveControl::Continue
+    }
+
+    fn idle(&mut self, now: SystemTime) {
+        let event = self.ring.complete(now);
+        self.hand_over(event);
+    }
+
+    fn finish(mut self) -> PacketStore {
+        let event = self.ring.finish();
+        self.hand_over(event);
+        PacketStore::default()
+    }
+}
+
+impl CaptureLoop for RotatingWriter {
+    fn packet(&mut self, packet: &[u8], timestamp: SystemTime, _: &CaptureCounters) -> LiveControl {
+        if let Err(e) = self.write_packet(timestamp, packet) {
+            panic!("Could not write packet to file: {e}");
+        }
+        LiveControl::Continue
+    }
+
+    fn finish(mut self) -> PacketStore {
+        if let Err(e) = self.close() {
+            panic!("Could not complete capture file: {e}");
+        }
+        PacketStore::default()
+    }
+}
@@ -0,0 +1,222 @@
+use crate::dissection::{validate_tcp, validate_udp};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::util;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Outcome of checking a checksum
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChecksumStatus {
+    /// The checksum matches the data
+    Valid,
+    /// The checksum doesn't match the data, so the packet was corrupted or crafted
+    Invalid,
+    /// The TCP or UDP checksum was left for the network card to compute, as seen on packets captured while being sent
+    ///
+    /// The field then holds zero, or only the sum of the pseudo-header. Only reported for senders a ChecksumOffload
+    /// allows, since the same field on any other packet means it was corrupted or crafted
+    Offloaded,
+    /// The sender didn't compute a checksum, which UDP over IPv4 allows
+    Absent,
+}
+
+/// Which senders may leave their TCP and UDP checksums for the network card to compute
+///
+/// Linux computes IPv4 header checksums itself, so those are never taken as offloaded
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumOffload {
+    /// Every checksum that doesn't match is invalid
+    #[default]
+    Never,
+    /// Packets sent from these addresses, usually those of the capturing host, may carry offloaded checksums
+    FromAddresses(Vec<IpAddr>),
+    /// Any packet may carry offloaded checksums
+    Always,
+}
+
+impl ChecksumOffload {
+    /// Return true if packets sent from `source` may carry offloaded checksums
+    pub fn allows(&self, source: IpAddr) -> bool {
+        match self {
+            ChecksumOffload::Never => false,
+            ChecksumOffload::FromAddresses(addresses) => addresses.contains(&source),
+            ChecksumOffload::Always => true,
+        }
+    }
+}
+
+/// Addresses the checksum of a transport header covers
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum PseudoHeader {
+    V4(Ipv4Addr, Ipv4Addr),
+    V6(Ipv6Addr, Ipv6Addr),
+}
+
+impl PseudoHeader {
+    /// Returns `None` if the addresses aren't both IPv4 or both IPv6
+    pub(crate) fn new(source: IpAddr, destination: IpAddr) -> Option<PseudoHeader> {
+        match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                Some(PseudoHeader::V4(source, destination))
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                Some(PseudoHeader::V6(source, destination))
+            }
+            _ => None,
+        }
+    }
+
+    /// Checksum of a message along with the pseudo-header, skipping the 16 bit word holding the checksum
+    pub(crate) fn checksum(
+        &self,
+        message: &[u8],
+        skipword: usize,
+        protocol: IpNextHeaderProtocol,
+    ) -> u16 {
+        match self {
+            PseudoHeader::V4(source, destination) => {
+                util::ipv4_checksum(message, skipword, &[], source, destination, protocol)
+            }
+            PseudoHeader::V6(source, destination) => {
+                util::ipv6_checksum(message, skipword, &[], source, destination, protocol)
+            }
+        }
+    }
+
+    /// Folded sum of the pseudo-header alone, which is what a sender offloading the checksum leaves in the field
+    fn partial_sum(&self, length: usize, protocol: IpNextHeaderProtocol) -> u16 {
+        let addresses = match self {
+            PseudoHeader::V4(source, destination) => {
+                [source.octets(), destination.octets()].concat()
+            }
+            PseudoHeader::V6(source, destination) => {
+                [source.octets(), destination.octets()].concat()
+            }
+        };
+        let mut sum = addresses
+            .chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+            .sum::<u32>()
+            + u32::from(protocol.0)
+            + (length as u32 >> 16)
+            + (length as u32 & 0xffff);
+        while sum >> 16 != 0 {
+            sum = (sum >> 16) + (sum & 0xffff);
+        }
+        sum as u16
+    }
+}
+
+/// Check the header checksum of a well formed raw ipv4 packet
+pub(crate) fn ipv4_header_status(packet: &[u8]) -> ChecksumStatus {
+    let header_length = usize::from(packet[0] & 0x0f) * 4;
+    let Some(header) = packet
+        .get(..header_length)
+        .filter(|header| header.len() >= 20)
+    else {
+        return ChecksumStatus::Invalid;
+    };
+    let field = u16::from_be_bytes([header[10], header[11]]);
+    if field == util::checksum(header, 5) {
+        ChecksumStatus::Valid
+    } else {
+        ChecksumStatus::Invalid
+    }
+}
+
+/// Find the part of a TCP, UDP, ICMP or ICMPv6 message its checksum covers, and where the checksum sits in it
+fn checksummed(message: &[u8], protocol: IpNextHeaderProtocol) -> Option<(&[u8], usize)> {
+    match protocol {
+        IpNextHeaderProtocols::Tcp => validate_tcp(message).ok().map(|_| (message, 16)),
+        IpNextHeaderProtocols::Udp => validate_udp(message).ok().map(|_| {
+            let length = usize::from(u16::from_be_bytes([message[4], message[5]]));
+            (&message[..length], 6)
+        }),
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 if message.len() >= 4 => {
+            Some((message, 2))
+        }
+        _ => None,
+    }
+}
+
+/// Compute the checksum a complete TCP, UDP, ICMP or ICMPv6 message should carry
+fn expected_checksum(
+    message: &[u8],
+    offset: usize,
+    protocol: IpNextHeaderProtocol,
+    pseudo_header: PseudoHeader,
+) -> u16 {
+    match protocol {
+        IpNextHeaderProtocols::Icmp => util::checksum(message, offset / 2),
+        // A computed checksum of zero is sent as all ones, since zero means none was computed
+        IpNextHeaderProtocols::Udp => match pseudo_header.checksum(message, offset / 2, protocol) {
+            0 => 0xffff,
+            checksum => checksum,
+        },
+        _ => pseudo_header.checksum(message, offset / 2, protocol),
+    }
+}
+
+/// Check the checksum of a complete TCP, UDP, ICMP or ICMPv6 message
+///
+/// TCP and UDP checksums holding what a sender offloading them leaves are only taken as offloaded if `offloaded` is true.
+/// Returns None for other protocols and malformed messages
+pub(crate) fn transport_status(
+    message: &[u8],
+    protocol: IpNextHeaderProtocol,
+    pseudo_header: PseudoHeader,
+    offloaded: bool,
+) -> Option<ChecksumStatus> {
+    let (message, offset) = checksummed(message, protocol)?;
+    let field = u16::from_be_bytes([message[offset], message[offset + 1]]);
+    let offloadable = offloaded
+        && matches!(
+            protocol,
+            IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp
+        );
+    let partial_sum = pseudo_header.partial_sum(message.len(), protocol);
+    Some(
+        if protocol == IpNextHeaderProtocols::Udp
+            && field == 0
+            && matches!(pseudo_header, PseudoHeader::V4(..))
+        {
+            ChecksumStatus::Absent
+        } else if field == expected_checksum(message, offset, protocol, pseudo_header) {
+            ChecksumStatus::Valid
+        } else if offloadable && (field == 0 || field == partial_sum || field == !partial_sum) {
+            ChecksumStatus::Offloaded
+        } else {
+            ChecksumStatus::Invalid
+        },
+    )
+}
+
+/// Update a checksum for the 16 bit words that changed from `original` to `rewritten`, as in RFC 1624
+pub(crate) fn update_checksum(checksum: u16, original: &[u8], rewritten: &[u8]) -> u16 {
+    let mut sum = u32::from(!checksum);
+    for (original, rewritten) in original.chunks(2).zip(rewritten.chunks(2)) {
+        sum += u32::from(!u16::from_be_bytes([original[0], original[1]]))
+            + u32::from(u16::from_be_bytes([rewritten[0], rewritten[1]]));
+    }
+    while sum >> 16 != 0 {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+    !(sum as u16)
+}
+
+/// Recompute the checksum of a complete TCP, UDP, ICMP or ICMPv6 message
+///
+/// UDP over IPv4 without a checksum is left without one
+pub(crate) fn fix_transport_checksum(
+    message: &mut [u8],
+    protocol: IpNextHeaderProtocol,
+    pseudo_header: PseudoHeader,
+) {
+    if transport_status(message, protocol, pseudo_header, false) == Some(ChecksumStatus::Absent) {
+        return;
+    }
+    let Some((checked, offset)) = checksummed(message, protocol) else {
+        return;
+    };
+    let checksum = expected_checksum(checked, offset, protocol, pseudo_header);
+    message[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+}
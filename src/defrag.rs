@@ -0,0 +1,580 @@
+use crate::dissection::{validate_ipv4, validate_ipv6};
+use crate::PacketCollection;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::util;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime};
+
+/// Largest IPv4 packet, or IPv6 payload, a reassembled packet can fill
+const MAX_PACKET_LENGTH: usize = 65535;
+
+/// Which data wins when fragments overlap, after the reassembly policies of different operating systems
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Data received first wins, as Windows does
+    #[default]
+    First,
+    /// Data received last wins, as Cisco IOS does
+    Last,
+    /// Data received first wins, unless the later fragment starts before the earlier one
+    Bsd,
+    /// Data received first wins, unless the later fragment starts before or at the same offset as the earlier one
+    Linux,
+}
+
+impl OverlapPolicy {
+    /// Return true if a fragment starting at `offset` overwrites data from a fragment starting at `existing_offset`
+    fn overwrites(&self, offset: usize, existing_offset: usize) -> bool {
+        match self {
+            OverlapPolicy::First => false,
+            OverlapPolicy::Last => true,
+            OverlapPolicy::Bsd => offset < existing_offset,
+            OverlapPolicy::Linux => offset <= existing_offset,
+        }
+    }
+}
+
+/// Something wrong with a set of fragments
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FragmentIssue {
+    /// Fragments were still missing once every packet was read
+    Incomplete,
+    /// Fragments were still missing when the timeout ran out, or the set was dropped to stay within the limits
+    TimedOut,
+    /// Fragments covered the same data with the same bytes
+    Overlap,
+    /// Fragments covered the same data with different bytes, which is how IDS evasion works
+    ConflictingOverlap,
+    /// The first fragment is too short to hold the whole TCP, UDP or ICMP header
+    TinyFirstFragment,
+    /// Last fragments disagreed on where the packet ends, or data was sent past the end
+    LengthMismatch,
+    /// The reassembled packet would be longer than an IP packet can be, so the set's data was dropped
+    Oversized,
+}
+
+/// Report on a set of fragments that couldn't be reassembled or looked suspicious
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FragmentSetReport {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub protocol: IpNextHeaderProtocol,
+    pub identification: u32,
+    /// Number of fragments received in the set
+    pub fragments: usize,
+    /// Time the first fragment was captured, if known
+    pub first_seen: Option<SystemTime>,
+    /// Whether a packet was reassembled from the set
+    pub reassembled: bool,
+    pub issues: Vec<FragmentIssue>,
+}
+
+/// Outcome of reassembling fragmented packets
+#[derive(Clone, Debug)]
+pub struct Defragmentation<T> {
+    /// Packets that weren't fragmented along with packets reassembled from fragments, in the order they were completed
+    pub packets: PacketCollection<T>,
+    /// Sets of fragments that couldn't be reassembled or looked suspicious
+    pub reports: Vec<FragmentSetReport>,
+}
+
+/// Reassembles fragmented IPv4 and IPv6 packets
+#[derive(Clone, Copy, Debug)]
+pub struct Defragmenter {
+    policy: OverlapPolicy,
+    timeout: Duration,
+    max_sets: usize,
+    max_buffered_bytes: usize,
+}
+
+/// Fields that tell fragment sets apart
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: IpNextHeaderProtocol,
+    identification: u32,
+}
+
+/// A fragment pulled out of a raw IP packet
+struct Fragment<'p> {
+    key: FragmentKey,
+    offset: usize,
+    more_fragments: bool,
+    /// Headers that are repeated in every fragment and start the reassembled packet
+    header: &'p [u8],
+    /// Position in the header of the ipv6 next header field pointing at the fragment header
+    next_header_index: Option<usize>,
+    data: &'p [u8],
+}
+
+/// Data of a fragment, or what is left of it after later fragments overwrote parts of it
+struct Piece {
+    start: usize,
+    /// Offset of the fragment the data was taken from
+    owner: usize,
+    data: Vec<u8>,
+}
+
+impl Piece {
+    fn end(&self) -> usize {
+        self.start + self.data.len()
+    }
+}
+
+/// Fragments received so far for a packet
+struct FragmentSet {
+    sequence: usize,
+    first_seen: Option<SystemTime>,
+    fragments: usize,
+    header: Option<Vec<u8>>,
+    /// Data received so far, sorted by offset and never overlapping
+    pieces: Vec<Piece>,
+    end: Option<usize>,
+    /// Bytes of header and data held by the set
+    buffered: usize,
+    issues: Vec<FragmentIssue>,
+}
+
+impl FragmentSet {
+    fn new(sequence: usize, first_seen: Option<SystemTime>) -> FragmentSet {
+        FragmentSet {
+            sequence,
+            first_seen,
+            fragments: 0,
+            header: None,
+            pieces: vec![],
+            end: None,
+            buffered: 0,
+            issues: vec![],
+        }
+    }
+
+    fn flag(&mut self, issue: FragmentIssue) {
+        if !self.issues.contains(&issue) {
+            self.issues.push(issue);
+        }
+    }
+
+    fn is_oversized(&self) -> bool {
+        self.issues.contains(&FragmentIssue::Oversized)
+    }
+
+    fn insert(&mut self, fragment: &Fragment<'_>, policy: OverlapPolicy) {
+        self.fragments += 1;
+        let (start, stop) = (fragment.offset, fragment.offset + fragment.data.len());
+        if self.is_oversized() {
+            return;
+        }
+        if stop > MAX_PACKET_LENGTH {
+            // Nothing can be rebuilt from the set anymore, so its fragments are only counted from here on
+            self.flag(FragmentIssue::Oversized);
+            self.header = None;
+            self.pieces = vec![];
+            self.buffered = 0;
+            return;
+        }
+        if fragment.offset == 0 {
+            if fragment.data.len() < transport_header_length(fragment.key.protocol) {
+                self.flag(FragmentIssue::TinyFirstFragment);
+            }
+            if self.header.is_none() {
+                let mut header = fragment.header.to_vec();
+                if let Some(index) = fragment.next_header_index {
+                    header[index] = fragment.key.protocol.0;
+                }
+                self.buffered += header.len();
+                self.header = Some(header);
+            }
+        }
+        if !fragment.more_fragments {
+            match self.end {
+                Some(end) if end != stop => self.flag(FragmentIssue::LengthMismatch),
+                _ => self.end = Some(stop),
+            }
+        }
+        if start == stop {
+            return;
+        }
+        // Take out the pieces the fragment overlaps, then put back what the policy keeps of them
+        let first = self.pieces.partition_point(|piece| piece.end() <= start);
+        let last = first + self.pieces[first..].partition_point(|piece| piece.start < stop);
+        let overlapped: Vec<Piece> = self.pieces.drain(first..last).collect();
+        let mut kept = vec![];
+        // Parts of the fragment covered by data the policy keeps
+        let mut covered = vec![];
+        for piece in overlapped {
+            let (overlap_start, overlap_stop) = (piece.start.max(start), piece.end().min(stop));
+            let existing = &piece.data[overlap_start - piece.start..overlap_stop - piece.start];
+            let received = &fragment.data[overlap_start - start..overlap_stop - start];
+            self.flag(if existing == received {
+                FragmentIssue::Overlap
+            } else {
+                FragmentIssue::ConflictingOverlap
+            });
+            if !policy.overwrites(start, piece.owner) {
+                covered.push((overlap_start, overlap_stop));
+                kept.push(piece);
+                continue;
+            }
+            self.buffered -= overlap_stop - overlap_start;
+            if piece.start < overlap_start {
+                kept.push(Piece {
+                    start: piece.start,
+                    owner: piece.owner,
+                    data: piece.data[..overlap_start - piece.start].to_vec(),
+                });
+            }
+            if overlap_stop < piece.end() {
+                kept.push(Piece {
+                    start: overlap_stop,
+                    owner: piece.owner,
+                    data: piece.data[overlap_stop - piece.start..].to_vec(),
+                });
+            }
+        }
+        let mut position = start;
+        for (covered_start, covered_stop) in covered.into_iter().chain([(stop, stop)]) {
+            if position < covered_start {
+                self.buffered += covered_start - position;
+                kept.push(Piece {
+                    start: position,
+                    owner: start,
+                    data: fragment.data[position - start..covered_start - start].to_vec(),
+                });
+            }
+            position = covered_stop;
+        }
+        kept.sort_by_key(|piece| piece.start);
+        self.pieces.splice(first..first, kept);
+    }
+
+    fn is_complete(&self) -> bool {
+        let Some(end) = self.end.filter(|_| self.header.is_some()) else {
+            return false;
+        };
+        let mut position = 0;
+        for piece in &self.pieces {
+            if piece.start != position || position >= end {
+                break;
+            }
+            position = piece.end();
+        }
+        position >= end
+    }
+
+    /// Put the data of a complete set together, cut at its end
+    fn data(&self, end: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(end);
+        for piece in &self.pieces {
+            if piece.start >= end {
+                break;
+            }
+            data.extend_from_slice(&piece.data[..piece.data.len().min(end - piece.start)]);
+        }
+        data
+    }
+
+    fn report(self, key: FragmentKey, reassembled: bool) -> FragmentSetReport {
+        FragmentSetReport {
+            source: key.source,
+            destination: key.destination,
+            protocol: key.protocol,
+            identification: key.identification,
+            fragments: self.fragments,
+            first_seen: self.first_seen,
+            reassembled,
+            issues: self.issues,
+        }
+    }
+}
+
+/// Fragment sets still waiting for fragments during a reassembly
+#[derive(Default)]
+struct PendingSets {
+    sets: HashMap<FragmentKey, FragmentSet>,
+    /// Keys of the sets by the position of their first fragment, oldest first
+    arrivals: BTreeMap<usize, FragmentKey>,
+    buffered: usize,
+}
+
+impl PendingSets {
+    fn remove(&mut self, key: &FragmentKey) -> Option<FragmentSet> {
+        let set = self.sets.remove(key)?;
+        self.arrivals.remove(&set.sequence);
+        self.buffered -= set.buffered;
+        Some(set)
+    }
+
+    /// Give up on the set with the oldest first fragment
+    fn evict_oldest(&mut self, reports: &mut Vec<FragmentSetReport>) {
+        if let Some((_, key)) = self.arrivals.first_key_value() {
+            let key = *key;
+            let mut set = self.remove(&key).unwrap();
+            set.flag(FragmentIssue::TimedOut);
+            reports.push(set.report(key, false));
+        }
+    }
+}
+
+/// Packet handed back by the reassembly
+pub(crate) enum Reassembled {
+    /// The packet at this position of the input, which wasn't a fragment
+    Unchanged(usize),
+    Rebuilt(Vec<u8>),
+}
+
+impl Default for Defragmenter {
+    fn default() -> Self {
+        Defragmenter::new(OverlapPolicy::default())
+    }
+}
+
+impl Defragmenter {
+    /// Create a Defragmenter that gives up on fragment sets after 30 seconds, as Linux does for IPv4
+    ///
+    /// At most 4096 sets and 4 MiB of fragment data are held at a time, the same amount Linux holds per network namespace
+    pub fn new(policy: OverlapPolicy) -> Defragmenter {
+        Defragmenter {
+            policy,
+            timeout: Duration::from_secs(30),
+            max_sets: 4096,
+            max_buffered_bytes: 4 * 1024 * 1024,
+        }
+    }
+
+    /// Give up on fragment sets that aren't complete `timeout` after their first fragment was captured
+    ///
+    /// Only applies to packets with a timestamp, such as the results of a completed capture
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Hold at most `max_sets` incomplete fragment sets, giving up on the oldest ones to make room for new ones
+    ///
+    /// Panics if `max_sets` is zero
+    pub fn set_max_sets(&mut self, max_sets: usize) {
+        assert!(max_sets > 0, "max_sets must be at least 1");
+        self.max_sets = max_sets;
+    }
+
+    /// Hold at most `max_buffered_bytes` bytes of incomplete fragment sets, giving up on the oldest ones past that
+    pub fn set_max_buffered_bytes(&mut self, max_buffered_bytes: usize) {
+        self.max_buffered_bytes = max_buffered_bytes;
+    }
+
+    /// Reassemble raw IPv4 packets
+    pub(crate) fn reassemble_ipv4<'p>(
+        &self,
+        packets: impl IntoIterator<Item = (Option<SystemTime>, &'p [u8])>,
+    ) -> (Vec<Reassembled>, Vec<FragmentSetReport>) {
+        self.reassemble(packets, ipv4_fragment, rebuild_ipv4)
+    }
+
+    /// Reassemble raw IPv6 packets
+    pub(crate) fn reassemble_ipv6<'p>(
+        &self,
+        packets: impl IntoIterator<Item = (Option<SystemTime>, &'p [u8])>,
+    ) -> (Vec<Reassembled>, Vec<FragmentSetReport>) {
+        self.reassemble(packets, ipv6_fragment, rebuild_ipv6)
+    }
+
+    fn reassemble<'p>(
+        &self,
+        packets: impl IntoIterator<Item = (Option<SystemTime>, &'p [u8])>,
+        parse: fn(&[u8]) -> Option<Fragment<'_>>,
+        rebuild: fn(Vec<u8>, &[u8]) -> Option<Vec<u8>>,
+    ) -> (Vec<Reassembled>, Vec<FragmentSetReport>) {
+        let mut reassembled = vec![];
+        let mut reports = vec![];
+        let mut pending = PendingSets::default();
+        let mut deadlines: VecDeque<(SystemTime, FragmentKey)> = VecDeque::new();
+        for (index, (timestamp, packet)) in packets.into_iter().enumerate() {
+            let Some(fragment) = parse(packet) else {
+                reassembled.push(Reassembled::Unchanged(index));
+                continue;
+            };
+            if let Some(now) = timestamp {
+                while let Some((deadline, key)) = deadlines.front().copied() {
+                    if deadline > now {
+                        break;
+                    }
+                    deadlines.pop_front();
+                    let expired = pending.sets.get(&key).is_some_and(|set| {
+                        set.first_seen.map(|first_seen| first_seen + self.timeout) == Some(deadline)
+                    });
+                    if expired {
+                        let mut set = pending.remove(&key).unwrap();
+                        set.flag(FragmentIssue::TimedOut);
+                        reports.push(set.report(key, false));
+                    }
+                }
+            }
+            if !pending.sets.contains_key(&fragment.key) {
+                while pending.sets.len() >= self.max_sets {
+                    pending.evict_oldest(&mut reports);
+                }
+                if let Some(first_seen) = timestamp {
+                    deadlines.push_back((first_seen + self.timeout, fragment.key));
+                }
+                pending.arrivals.insert(index, fragment.key);
+                pending
+                    .sets
+                    .insert(fragment.key, FragmentSet::new(index, timestamp));
+            }
+            let set = pending.sets.get_mut(&fragment.key).unwrap();
+            let previously_buffered = set.buffered;
+            set.insert(&fragment, self.policy);
+            pending.buffered = pending.buffered - previously_buffered + set.buffered;
+            if set.is_complete() {
+                let mut set = pending.remove(&fragment.key).unwrap();
+                let end = set.end.unwrap();
+                if set.pieces.last().is_some_and(|piece| piece.end() > end) {
+                    set.flag(FragmentIssue::LengthMismatch);
+                }
+                match rebuild(set.data(end), set.header.as_deref().unwrap()) {
+                    Some(packet) => {
+                        reassembled.push(Reassembled::Rebuilt(packet));
+                        if !set.issues.is_empty() {
+                            reports.push(set.report(fragment.key, true));
+                        }
+                    }
+                    None => {
+                        set.flag(FragmentIssue::Oversized);
+                        reports.push(set.report(fragment.key, false));
+                    }
+                }
+            }
+            while pending.buffered > self.max_buffered_bytes {
+                pending.evict_oldest(&mut reports);
+            }
+        }
+        for (_, key) in mem::take(&mut pending.arrivals) {
+            let mut set = pending.sets.remove(&key).unwrap();
+            set.flag(FragmentIssue::Incomplete);
+            reports.push(set.report(key, false));
+        }
+        (reassembled, reports)
+    }
+}
+
+/// Shortest header of a transport protocol, which a first fragment should hold whole
+fn transport_header_length(protocol: IpNextHeaderProtocol) -> usize {
+    match protocol {
+        IpNextHeaderProtocols::Tcp => 20,
+        IpNextHeaderProtocols::Udp
+        | IpNextHeaderProtocols::Icmp
+        | IpNextHeaderProtocols::Icmpv6 => 8,
+        _ => 0,
+    }
+}
+
+/// Pull the fragment out of a raw ipv4 packet, if it is a well formed fragment
+fn ipv4_fragment(packet: &[u8]) -> Option<Fragment<'_>> {
+    validate_ipv4(packet).ok()?;
+    let flags_and_offset = u16::from_be_bytes([packet[6], packet[7]]);
+    let more_fragments = flags_and_offset & 0x2000 != 0;
+    let offset = usize::from(flags_and_offset & 0x1fff) * 8;
+    if !more_fragments && offset == 0 {
+        return None;
+    }
+    let header_length = usize::from(packet[0] & 0x0f) * 4;
+    let total_length = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    Some(Fragment {
+        key: FragmentKey {
+            source: IpAddr::V4(Ipv4Addr::new(
+                packet[12], packet[13], packet[14], packet[15],
+            )),
+            destination: IpAddr::V4(Ipv4Addr::new(
+                packet[16], packet[17], packet[18], packet[19],
+            )),
+            protocol: IpNextHeaderProtocol(packet[9]),
+            identification: u32::from(u16::from_be_bytes([packet[4], packet[5]])),
+        },
+        offset,
+        more_fragments,
+        header: &packet[..header_length],
+        next_header_index: None,
+        data: &packet[header_length..total_length],
+    })
+}
+
+/// Put an ipv4 header back in front of reassembled data, clearing its fragment fields
+fn rebuild_ipv4(data: Vec<u8>, header: &[u8]) -> Option<Vec<u8>> {
+    let total_length = header.len() + data.len();
+    if total_length > MAX_PACKET_LENGTH {
+        return None;
+    }
+    let mut packet = header.to_vec();
+    packet[2..4].copy_from_slice(&(total_length as u16).to_be_bytes());
+    // Keep only the don't fragment flag
+    packet[6] &= 0x40;
+    packet[7] = 0;
+    let checksum = util::checksum(&packet, 5);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&data);
+    Some(packet)
+}
+
+/// Pull the fragment out of a raw ipv6 packet, if it is well formed and carries a fragment header
+///
+/// The header of the fragment covers the ipv6 header and the extension headers in front of the fragment header
+fn ipv6_fragment(packet: &[u8]) -> Option<Fragment<'_>> {
+    validate_ipv6(packet).ok()?;
+    let end = 40 + usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+    let (mut next_header_index, mut position) = (6, 40);
+    loop {
+        let next_header = IpNextHeaderProtocol(packet[next_header_index]);
+        let extension_length = match next_header {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts => (usize::from(*packet.get(position + 1)?) + 1) * 8,
+            IpNextHeaderProtocols::Ipv6Frag => break,
+            _ => return None,
+        };
+        if position + extension_length > end {
+            return None;
+        }
+        (next_header_index, position) = (position, position + extension_length);
+    }
+    let fragment_header = packet
+        .get(position..position + 8)
+        .filter(|_| position + 8 <= end)?;
+    let flags_and_offset = u16::from_be_bytes([fragment_header[2], fragment_header[3]]);
+    let mut source = [0u8; 16];
+    source.copy_from_slice(&packet[8..24]);
+    let mut destination = [0u8; 16];
+    destination.copy_from_slice(&packet[24..40]);
+    Some(Fragment {
+        key: FragmentKey {
+            source: IpAddr::V6(Ipv6Addr::from(source)),
+            destination: IpAddr::V6(Ipv6Addr::from(destination)),
+            protocol: IpNextHeaderProtocol(fragment_header[0]),
+            identification: u32::from_be_bytes([
+                fragment_header[4],
+                fragment_header[5],
+                fragment_header[6],
+                fragment_header[7],
+            ]),
+        },
+        offset: usize::from(flags_and_offset >> 3) * 8,
+        more_fragments: flags_and_offset & 1 != 0,
+        header: &packet[..position],
+        next_header_index: Some(next_header_index),
+        data: &packet[position + 8..end],
+    })
+}
+
+/// Put the ipv6 header and unfragmentable extension headers back in front of reassembled data
+fn rebuild_ipv6(data: Vec<u8>, header: &[u8]) -> Option<Vec<u8>> {
+    let payload_length = header.len() - 40 + data.len();
+    if payload_length > MAX_PACKET_LENGTH {
+        return None;
+    }
+    let mut packet = header.to_vec();
+    packet[4..6].copy_from_slice(&(payload_length as u16).to_be_bytes());
+    packet.extend_from_slice(&data);
+    Some(packet)
+}
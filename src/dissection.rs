@@ -0,0 +1,363 @@
+use crate::ethernet_frame::strip_frame_tags;
+use crate::ipv4_packet::ipv4_payload;
+use crate::ipv6_packet::upper_layer;
+use crate::tunnel::{self, Decapsulation};
+use crate::PacketCollection;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket as pnet_EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet as pnet_Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet as pnet_Ipv6Packet;
+use pnet::packet::tcp::TcpPacket as pnet_TcpPacket;
+use pnet::packet::udp::UdpPacket as pnet_UdpPacket;
+use std::error::Error;
+use std::fmt;
+
+/// Layers of the network stack wiretap dissects
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ProtocolLayer {
+    Link,
+    Network,
+    Transport,
+    Application,
+}
+
+/// Reason a packet could not be dissected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Malformation {
+    /// The buffer ends before the header of the layer does
+    Truncated {
+        layer: ProtocolLayer,
+        length: usize,
+        needed: usize,
+    },
+    /// The ipv4 version field isn't 4
+    Ipv4Version(u8),
+    /// The ipv4 IHL is below the minimum of 5 words or runs past the end of the packet
+    Ipv4HeaderLength(u8),
+    /// The ipv4 total length is smaller than the header or larger than the packet
+    Ipv4TotalLength(u16),
+    /// The ipv6 version field isn't 6
+    Ipv6Version(u8),
+    /// The ipv6 payload length runs past the end of the packet
+    Ipv6PayloadLength(u16),
+    /// The tcp data offset is below the minimum of 5 words or runs past the end of the segment
+    TcpDataOffset(u8),
+    /// The udp length is smaller than the header or larger than the datagram
+    UdpLength(u16),
+}
+
+impl Malformation {
+    /// Get the layer at which the packet stopped making sense
+    pub fn layer(&self) -> ProtocolLayer {
+        match self {
+            Malformation::Truncated { layer, .. } => *layer,
+            Malformation::Ipv4Version(_)
+            | Malformation::Ipv4HeaderLength(_)
+            | Malformation::Ipv4TotalLength(_)
+            | Malformation::Ipv6Version(_)
+            | Malformation::Ipv6PayloadLength(_) => ProtocolLayer::Network,
+            Malformation::TcpDataOffset(_) | Malformation::UdpLength(_) => ProtocolLayer::Transport,
+        }
+    }
+}
+
+impl fmt::Display for Malformation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Malformation::Truncated {
+                layer,
+                length,
+                needed,
+            } => write!(
+                f,
+                "{layer:?} header needs {needed} bytes but only {length} are left"
+            ),
+            Malformation::Ipv4Version(version) => write!(f, "ipv4 header has version {version}"),
+            Malformation::Ipv4HeaderLength(ihl) => write!(f, "ipv4 header has invalid IHL {ihl}"),
+            Malformation::Ipv4TotalLength(total_length) => {
+                write!(f, "ipv4 header has invalid total length {total_length}")
+            }
+            Malformation::Ipv6Version(version) => write!(f, "ipv6 header has version {version}"),
+            Malformation::Ipv6PayloadLength(payload_length) => {
+                write!(f, "ipv6 header has invalid payload length {payload_length}")
+            }
+            Malformation::TcpDataOffset(data_offset) => {
+                write!(f, "tcp header has invalid data offset {data_offset}")
+            }
+            Malformation::UdpLength(length) => write!(f, "udp header has invalid length {length}"),
+        }
+    }
+}
+
+impl Error for Malformation {}
+
+/// Check that a buffer is long enough to hold an ethernet header
+pub(crate) fn validate_ethernet(frame: &[u8]) -> Result<(), Malformation> {
+    let needed = pnet_EthernetPacket::minimum_packet_size();
+    if frame.len() < needed {
+        return Err(Malformation::Truncated {
+            layer: ProtocolLayer::Link,
+            length: frame.len(),
+            needed,
+        });
+    }
+    Ok(())
+}
+
+/// Check that a buffer holds a well formed ipv4 header
+pub(crate) fn validate_ipv4(packet: &[u8]) -> Result<(), Malformation> {
+    let needed = pnet_Ipv4Packet::minimum_packet_size();
+    if packet.len() < needed {
+        return Err(Malformation::Truncated {
+            layer: ProtocolLayer::Network,
+            length: packet.len(),
+            needed,
+        });
+    }
+    let version = packet[0] >> 4;
+    if version != 4 {
+        return Err(Malformation::Ipv4Version(version));
+    }
+    let ihl = packet[0] & 0x0f;
+    let header_length = usize::from(ihl) * 4;
+    if header_length < needed || header_length > packet.len() {
+        return Err(Malformation::Ipv4HeaderLength(ihl));
+    }
+    let total_length = u16::from_be_bytes([packet[2], packet[3]]);
+    if usize::from(total_length) < header_length || usize::from(total_length) > packet.len() {
+        return Err(Malformation::Ipv4TotalLength(total_length));
+    }
+    Ok(())
+}
+
+/// Check that a buffer holds a well formed ipv6 header
+pub(crate) fn validate_ipv6(packet: &[u8]) -> Result<(), Malformation> {
+    let needed = pnet_Ipv6Packet::minimum_packet_size();
+    if packet.len() < needed {
+        return Err(Malformation::Truncated {
+            layer: ProtocolLayer::Network,
+            length: packet.len(),
+            needed,
+        });
+    }
+    let version = packet[0] >> 4;
+    if version != 6 {
+        return Err(Malformation::Ipv6Version(version));
+    }
+    let payload_length = u16::from_be_bytes([packet[4], packet[5]]);
+    if needed + usize::from(payload_length) > packet.len() {
+        return Err(Malformation::Ipv6PayloadLength(payload_length));
+    }
+    Ok(())
+}
+
+/// Check that a buffer holds a well formed tcp header
+pub(crate) fn validate_tcp(segment: &[u8]) -> Result<(), Malformation> {
+    let needed = pnet_TcpPacket::minimum_packet_size();
+    if segment.len() < needed {
+        return Err(Malformation::Truncated {
+            layer: ProtocolLayer::Transport,
+            length: segment.len(),
+            needed,
+        });
+    }
+    let data_offset = segment[12] >> 4;
+    let header_length = usize::from(data_offset) * 4;
+    if header_length < needed || header_length > segment.len() {
+        return Err(Malformation::TcpDataOffset(data_offset));
+    }
+    Ok(())
+}
+
+/// Check that a buffer holds a well formed udp header
+pub(crate) fn validate_udp(datagram: &[u8]) -> Result<(), Malformation> {
+    let needed = pnet_UdpPacket::minimum_packet_size();
+    if datagram.len() < needed {
+        return Err(Malformation::Truncated {
+            layer: ProtocolLayer::Transport,
+            length: datagram.len(),
+            needed,
+        });
+    }
+    let length = u16::from_be_bytes([datagram[4], datagram[5]]);
+    if usize::from(length) < needed || usize::from(length) > datagram.len() {
+        return Err(Malformation::UdpLength(length));
+    }
+    Ok(())
+}
+
+/// Follow a raw ethernet frame down to its ipv4 packet
+///
+/// Returns `Ok(None)` when the frame carries another network protocol
+pub(crate) fn frame_to_ipv4(
+    frame: &[u8],
+    decapsulation: Decapsulation,
+) -> Result<Option<&[u8]>, Malformation> {
+    validate_ethernet(frame)?;
+    let frame = match decapsulation {
+        Decapsulation::Outermost => frame,
+        Decapsulation::Innermost => tunnel::innermost_ethernet(frame),
+    };
+    let stripped = strip_frame_tags(frame);
+    if stripped.ethertype != EtherTypes::Ipv4 {
+        return Ok(None);
+    }
+    validate_ipv4(stripped.payload)?;
+    match decapsulation {
+        Decapsulation::Outermost => Ok(Some(stripped.payload)),
+        Decapsulation::Innermost => Ok(Some(tunnel::innermost_ipv4(stripped.payload))),
+    }
+}
+
+/// Check that a buffer holds a whole ICMP or ICMPv6 header
+pub(crate) fn validate_icmp(message: &[u8]) -> Result<(), Malformation> {
+    // Type, code and checksum are followed by four bytes whose meaning depends on the type
+    let needed = 8;
+    if message.len() < needed {
+        return Err(Malformation::Truncated {
+            layer: ProtocolLayer::Network,
+            length: message.len(),
+            needed,
+        });
+    }
+    Ok(())
+}
+
+/// Follow an ethernet frame down to its ipv6 packet, skipping VLAN tags and MPLS labels
+///
+/// Returns `Ok(None)` when the frame carries another protocol
+pub(crate) fn frame_to_ipv6(
+    frame: &[u8],
+    decapsulation: Decapsulation,
+) -> Result<Option<&[u8]>, Malformation> {
+    validate_ethernet(frame)?;
+    let frame = match decapsulation {
+        Decapsulation::Outermost => frame,
+        Decapsulation::Innermost => tunnel::innermost_ethernet(frame),
+    };
+    let stripped = strip_frame_tags(frame);
+    if stripped.ethertype != EtherTypes::Ipv6 {
+        return Ok(None);
+    }
+    validate_ipv6(stripped.payload)?;
+    Ok(Some(stripped.payload))
+}
+
+/// Step that follows a well formed raw packet down to the layer it carries
+type Dissector = fn(&[u8]) -> Result<Option<&[u8]>, Malformation>;
+
+/// Follow a raw ethernet frame through its ipv4 or ipv6 packet, down to what `ipv4_to` or `ipv6_to` find in it
+///
+/// Returns `Ok(None)` when the frame carries another network protocol
+pub(crate) fn frame_to_transport(
+    frame: &[u8],
+    decapsulation: Decapsulation,
+    ipv4_to: Dissector,
+    ipv6_to: Dissector,
+) -> Result<Option<&[u8]>, Malformation> {
+    if let Some(ipv4) = frame_to_ipv4(frame, decapsulation)? {
+        return ipv4_to(ipv4);
+    }
+    frame_to_ipv6(frame, decapsulation)?.map_or(Ok(None), ipv6_to)
+}
+
+/// Follow a well formed raw ipv4 packet down to its tcp segment
+///
+/// Returns `Ok(None)` when the packet carries another protocol or is a trailing fragment
+pub(crate) fn ipv4_to_tcp(packet: &[u8]) -> Result<Option<&[u8]>, Malformation> {
+    let ipv4_packet = pnet_Ipv4Packet::new(packet).unwrap();
+    if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp
+        || ipv4_packet.get_fragment_offset() != 0
+    {
+        return Ok(None);
+    }
+    let segment = ipv4_payload(packet);
+    validate_tcp(segment)?;
+    Ok(Some(segment))
+}
+
+/// Follow a well formed raw ipv4 packet down to its udp datagram
+///
+/// Returns `Ok(None)` when the packet carries another protocol or is a trailing fragment
+pub(crate) fn ipv4_to_udp(packet: &[u8]) -> Result<Option<&[u8]>, Malformation> {
+    let ipv4_packet = pnet_Ipv4Packet::new(packet).unwrap();
+    if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp
+        || ipv4_packet.get_fragment_offset() != 0
+    {
+        return Ok(None);
+    }
+    let datagram = ipv4_payload(packet);
+    validate_udp(datagram)?;
+    Ok(Some(datagram))
+}
+
+/// Follow a well formed raw ipv6 packet past its extension headers down to its tcp segment
+///
+/// Returns `Ok(None)` when the packet carries another protocol or is a trailing fragment
+pub(crate) fn ipv6_to_tcp(packet: &[u8]) -> Result<Option<&[u8]>, Malformation> {
+    let upper_layer = upper_layer(packet)?;
+    if upper_layer.protocol != IpNextHeaderProtocols::Tcp || upper_layer.trailing_fragment {
+        return Ok(None);
+    }
+    validate_tcp(upper_layer.payload)?;
+    Ok(Some(upper_layer.payload))
+}
+
+/// Follow a well formed raw ipv6 packet past its extension headers down to its udp datagram
+///
+/// Returns `Ok(None)` when the packet carries another protocol or is a trailing fragment
+pub(crate) fn ipv6_to_udp(packet: &[u8]) -> Result<Option<&[u8]>, Malformation> {
+    let upper_layer = upper_layer(packet)?;
+    if upper_layer.protocol != IpNextHeaderProtocols::Udp || upper_layer.trailing_fragment {
+        return Ok(None);
+    }
+    validate_udp(upper_layer.payload)?;
+    Ok(Some(upper_layer.payload))
+}
+
+/// A captured packet that could not be dissected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MalformedPacket {
+    /// Position of the packet in the raw results
+    pub index: usize,
+    pub malformation: Malformation,
+}
+
+/// Collection of MalformedPacket with the shared PacketCollection functionality
+pub type MalformedPacketCollection = PacketCollection<MalformedPacket>;
+
+impl MalformedPacketCollection {
+    /// Get a collection of MalformedPacket that broke at a layer
+    ///
+    /// Returns a new MalformedPacketCollection containing only the packets malformed at that layer
+    pub fn filter_layer(&self, layer: ProtocolLayer) -> MalformedPacketCollection {
+        self.par_filter(|p| p.malformation.layer() == layer)
+    }
+}
+
+/// Follow a well formed raw ipv4 packet down to its icmp message
+///
+/// Returns `Ok(None)` when the packet carries another protocol or is a trailing fragment
+pub(crate) fn ipv4_to_icmp(packet: &[u8]) -> Result<Option<&[u8]>, Malformation> {
+    let ipv4_packet = pnet_Ipv4Packet::new(packet).unwrap();
+    if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Icmp
+        || ipv4_packet.get_fragment_offset() != 0
+    {
+        return Ok(None);
+    }
+    let message = ipv4_payload(packet);
+    validate_icmp(message)?;
+    Ok(Some(message))
+}
+
+/// Follow a well formed raw ipv6 packet past its extension headers down to its icmpv6 message
+///
+/// Returns `Ok(None)` when the packet carries another protocol or is a trailing fragment
+pub(crate) fn ipv6_to_icmpv6(packet: &[u8]) -> Result<Option<&[u8]>, Malformation> {
+    let upper_layer = upper_layer(packet)?;
+    if upper_layer.protocol != IpNextHeaderProtocols::Icmpv6 || upper_layer.trailing_fragment {
+        return Ok(None);
+    }
+    validate_icmp(upper_layer.payload)?;
+    Ok(Some(upper_layer.payload))
+}
@@ -1,15 +1,19 @@
-use pnet::packet::ethernet::EthernetPacket as pnet_EthernetPacket;
+use crate::tunnel;
+use crate::PacketCollection;
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket as pnet_EthernetPacket};
+use pnet::packet::vlan::VlanPacket as pnet_VlanPacket;
 use pnet::packet::Packet;
+use std::fmt;
 use std::ops::Deref;
-use std::sync::Arc;
 
 /// Wrapper around pnet's EthernetPacket for adding additional funcitonality
-#[derive(Debug)]
-pub struct EthernetFrame<'a>(pnet_EthernetPacket<'a>);
+///
+/// A wrapper created from a byte slice remembers it, so cloning the wrapper doesn't copy the packet
+pub struct EthernetFrame<'a>(pnet_EthernetPacket<'a>, Option<&'a [u8]>);
 
 impl<'a> From<pnet_EthernetPacket<'a>> for EthernetFrame<'a> {
     fn from(ethernet_frame: pnet_EthernetPacket<'a>) -> Self {
-        EthernetFrame(ethernet_frame)
+        EthernetFrame(ethernet_frame, None)
     }
 }
 
@@ -21,30 +25,172 @@ impl<'a> Deref for EthernetFrame<'a> {
     }
 }
 
+impl Clone for EthernetFrame<'_> {
+    fn clone(&self) -> Self {
+        match self.1 {
+            Some(packet) => EthernetFrame::borrowed(packet),
+            None => self.create_clone(),
+        }
+    }
+}
+
+impl fmt::Debug for EthernetFrame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EthernetFrame").field(&self.0).finish()
+    }
+}
+
+impl<'a> EthernetFrame<'a> {
+    /// Wrap bytes already known to hold the header, without copying them
+    pub(crate) fn borrowed(packet: &'a [u8]) -> EthernetFrame<'a> {
+        EthernetFrame(pnet_EthernetPacket::new(packet).unwrap(), Some(packet))
+    }
+}
+
 impl EthernetFrame<'_> {
     pub fn new<'a>(packet: &'a [u8]) -> Option<EthernetFrame<'a>>{
-        pnet_EthernetPacket::new(packet).map(EthernetFrame::from)
+        pnet_EthernetPacket::new(packet).map(|frame| EthernetFrame(frame, Some(packet)))
     }
     
     pub fn create_clone<'a>(&self) -> EthernetFrame<'a> {
         EthernetFrame::from(pnet_EthernetPacket::owned(self.packet().to_vec()).unwrap())
     }
+
+    /// Get the 802.1Q / 802.1ad tags of the frame, outermost first
+    pub fn vlan_tags(&self) -> Vec<VlanTag> {
+        self.strip_tags().vlan_tags
+    }
+
+    /// Get the VLAN ids of the frame, outermost first
+    pub fn vlan_ids(&self) -> Vec<u16> {
+        self.vlan_tags().iter().map(|tag| tag.id).collect()
+    }
+
+    /// Get the MPLS label stack of the frame, top of the stack first
+    pub fn mpls_labels(&self) -> Vec<MplsLabel> {
+        self.strip_tags().mpls_labels
+    }
+
+    /// Get the EtherType of the payload once VLAN tags and MPLS labels are removed
+    ///
+    /// The payload of an MPLS label stack carries no EtherType, so it is guessed from the IP version nibble
+    pub fn inner_ethertype(&self) -> EtherType {
+        self.strip_tags().ethertype
+    }
+
+    /// Get the payload of the frame once VLAN tags and MPLS labels are removed
+    pub fn inner_payload(&self) -> &[u8] {
+        self.strip_tags().payload
+    }
+
+    /// Get the innermost ethernet frame, as carried by VXLAN, Geneve or GRE tunnels
+    ///
+    /// Returns a view of the frame itself when it doesn't carry an ethernet tunnel
+    pub fn innermost(&self) -> EthernetFrame<'_> {
+        EthernetFrame::borrowed(tunnel::innermost_ethernet(self.packet()))
+    }
+
+    fn strip_tags(&self) -> StrippedFrame<'_> {
+        strip_frame_tags(self.packet())
+    }
+}
+
+/// Walk past the VLAN tags and MPLS labels of a raw ethernet frame
+///
+/// The frame must be at least as long as an ethernet header
+pub(crate) fn strip_frame_tags(frame: &[u8]) -> StrippedFrame<'_> {
+    let header_length = pnet_EthernetPacket::minimum_packet_size();
+    let mut stripped = StrippedFrame {
+        vlan_tags: vec![],
+        mpls_labels: vec![],
+        ethertype: EtherType(u16::from_be_bytes([frame[12], frame[13]])),
+        payload: &frame[header_length..],
+    };
+    loop {
+        match stripped.ethertype {
+            EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ => {
+                let Some(tag) = pnet_VlanPacket::new(stripped.payload) else {
+                    break;
+                };
+                stripped.vlan_tags.push(VlanTag {
+                    tpid: stripped.ethertype,
+                    priority: tag.get_priority_code_point().0,
+                    drop_eligible: tag.get_drop_eligible_indicator() == 1,
+                    id: tag.get_vlan_identifier(),
+                });
+                stripped.ethertype = tag.get_ethertype();
+                stripped.payload = &stripped.payload[pnet_VlanPacket::minimum_packet_size()..];
+            }
+            EtherTypes::Mpls | EtherTypes::MplsMcast => {
+                let mut bottom_of_stack = false;
+                while !bottom_of_stack && stripped.payload.len() >= 4 {
+                    let label = MplsLabel::from_bytes(&stripped.payload[..4]);
+                    bottom_of_stack = label.bottom_of_stack;
+                    stripped.mpls_labels.push(label);
+                    stripped.payload = &stripped.payload[4..];
+                }
+                if !bottom_of_stack {
+                    break;
+                }
+                stripped.ethertype = match stripped.payload.first().map(|byte| byte >> 4) {
+                    Some(4) => EtherTypes::Ipv4,
+                    Some(6) => EtherTypes::Ipv6,
+                    _ => break,
+                };
+            }
+            _ => break,
+        }
+    }
+    stripped
+}
+
+/// Layers found between the Ethernet header and the network layer
+pub(crate) struct StrippedFrame<'a> {
+    pub(crate) vlan_tags: Vec<VlanTag>,
+    pub(crate) mpls_labels: Vec<MplsLabel>,
+    pub(crate) ethertype: EtherType,
+    pub(crate) payload: &'a [u8],
+}
+
+/// An 802.1Q (or 802.1ad service) tag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VlanTag {
+    /// Tag protocol identifier: 0x8100 for 802.1Q, 0x88a8 or 0x9100 for outer QinQ tags
+    pub tpid: EtherType,
+    pub priority: u8,
+    pub drop_eligible: bool,
+    pub id: u16,
 }
 
-/// Wrapper around an Arc<[EthernetFrame]> for additional functionality
-#[derive(Debug)]
-pub struct EthernetFrameCollection<'a>(Arc<[EthernetFrame<'a>]>);
+/// A single entry of an MPLS label stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MplsLabel {
+    pub label: u32,
+    pub traffic_class: u8,
+    pub bottom_of_stack: bool,
+    pub ttl: u8,
+}
 
-impl<'a> FromIterator<EthernetFrame<'a>> for EthernetFrameCollection<'a> {
-    fn from_iter<I: IntoIterator<Item = EthernetFrame<'a>>>(iter: I) -> Self {
-        EthernetFrameCollection(iter.into_iter().collect())
+impl MplsLabel {
+    fn from_bytes(bytes: &[u8]) -> MplsLabel {
+        let entry = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        MplsLabel {
+            label: entry >> 12,
+            traffic_class: ((entry >> 9) & 0x7) as u8,
+            bottom_of_stack: (entry >> 8) & 0x1 == 1,
+            ttl: (entry & 0xff) as u8,
+        }
     }
 }
 
-impl<'a> Deref for EthernetFrameCollection<'a> {
-    type Target = Arc<[EthernetFrame<'a>]>;
+/// Collection of EthernetFrame with the shared PacketCollection functionality
+pub type EthernetFrameCollection<'a> = PacketCollection<EthernetFrame<'a>>;
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl<'a> EthernetFrameCollection<'a> {
+    /// Get a collection of EthernetFrame on a VLAN
+    ///
+    /// Returns a new EthernetFrameCollection containing only the frames carrying a tag with the VLAN id, at any depth
+    pub fn filter_vlan(&'a self, vlan_id: u16) -> EthernetFrameCollection<'a> {
+        self.par_filter(|f| f.vlan_ids().contains(&vlan_id))
     }
 }
@@ -0,0 +1,396 @@
+use crate::checksum::{transport_status, ChecksumStatus, PseudoHeader};
+use crate::dissection::{validate_icmp, Malformation};
+use crate::layered_packet::hash_flow;
+use crate::PacketCollection;
+use pnet::packet::icmp::IcmpPacket as pnet_IcmpPacket;
+use pnet::packet::icmpv6::Icmpv6Packet as pnet_Icmpv6Packet;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::Packet;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Deref;
+
+/// Identifier and sequence number of an echo request or reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Echo {
+    pub identifier: u16,
+    pub sequence: u16,
+}
+
+/// An echo request along with the reply to it, if one was seen
+#[derive(Clone, Debug)]
+pub struct EchoPair<T> {
+    pub request: T,
+    pub reply: Option<T>,
+}
+
+/// The start of the packet that triggered an ICMP or ICMPv6 error, as quoted by the error
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EmbeddedPacket {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub protocol: IpNextHeaderProtocol,
+    /// Ports of TCP, UDP and SCTP packets, when the error quotes them
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    /// Whether the quoted packet is a fragment, whose flow hashes without the ports
+    pub fragmented: bool,
+}
+
+impl EmbeddedPacket {
+    /// Hash the flow of the packet the same way `LayeredPacket::flow_hash` does, to tie the error back to it
+    pub fn flow_hash(&self) -> u64 {
+        let (source_port, destination_port) = match self.fragmented {
+            true => (0, 0),
+            false => (
+                self.source_port.unwrap_or(0),
+                self.destination_port.unwrap_or(0),
+            ),
+        };
+        hash_flow(
+            self.source,
+            self.destination,
+            self.protocol.0,
+            source_port,
+            destination_port,
+        )
+    }
+}
+
+/// Wrapper around pnet's IcmpPacket for adding additional funcitonality
+///
+/// A wrapper created from a byte slice remembers it, so cloning the wrapper doesn't copy the packet
+pub struct IcmpPacket<'a>(pnet_IcmpPacket<'a>, Option<&'a [u8]>);
+
+impl<'a> From<pnet_IcmpPacket<'a>> for IcmpPacket<'a> {
+    fn from(icmp_packet: pnet_IcmpPacket<'a>) -> Self {
+        IcmpPacket(icmp_packet, None)
+    }
+}
+
+impl<'a> Deref for IcmpPacket<'a> {
+    type Target = pnet_IcmpPacket<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Clone for IcmpPacket<'_> {
+    fn clone(&self) -> Self {
+        match self.1 {
+            Some(packet) => IcmpPacket::borrowed(packet),
+            None => self.create_clone(),
+        }
+    }
+}
+
+impl fmt::Debug for IcmpPacket<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("IcmpPacket").field(&self.0).finish()
+    }
+}
+
+impl<'a> IcmpPacket<'a> {
+    /// Wrap bytes already known to hold the header, without copying them
+    pub(crate) fn borrowed(packet: &'a [u8]) -> IcmpPacket<'a> {
+        IcmpPacket(pnet_IcmpPacket::new(packet).unwrap(), Some(packet))
+    }
+}
+
+impl IcmpPacket<'_> {
+    pub fn new<'a>(packet: &'a [u8]) -> Option<IcmpPacket<'a>> {
+        IcmpPacket::try_new(packet).ok()
+    }
+
+    /// Create an IcmpPacket after checking the message holds the whole header
+    pub fn try_new(packet: &[u8]) -> Result<IcmpPacket<'_>, Malformation> {
+        validate_icmp(packet)?;
+        Ok(IcmpPacket::borrowed(packet))
+    }
+
+    pub fn create_clone<'a>(&self) -> IcmpPacket<'a> {
+        IcmpPacket::from(pnet_IcmpPacket::owned(self.packet().to_vec()).unwrap())
+    }
+
+    pub fn is_echo_request(&self) -> bool {
+        self.get_icmp_type().0 == 8
+    }
+
+    pub fn is_echo_reply(&self) -> bool {
+        self.get_icmp_type().0 == 0
+    }
+
+    /// Get the identifier and sequence number of an echo request or reply
+    pub fn echo(&self) -> Option<Echo> {
+        if !(self.is_echo_request() || self.is_echo_reply()) {
+            return None;
+        }
+        echo(self.packet())
+    }
+
+    /// Return true if the message reports an error with a packet, such as destination unreachable or time exceeded
+    pub fn is_error(&self) -> bool {
+        matches!(self.get_icmp_type().0, 3 | 4 | 5 | 11 | 12)
+    }
+
+    /// Decode the addresses, protocol and ports of the packet an error message was sent in response to
+    pub fn embedded_packet(&self) -> Option<EmbeddedPacket> {
+        if !self.is_error() {
+            return None;
+        }
+        embedded_ipv4(self.packet().get(8..)?)
+    }
+
+    /// Check the checksum, which covers only the message
+    pub fn checksum_status(&self) -> ChecksumStatus {
+        transport_status(
+            self.packet(),
+            IpNextHeaderProtocols::Icmp,
+            PseudoHeader::V4(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED),
+            false,
+        )
+        .unwrap_or(ChecksumStatus::Invalid)
+    }
+}
+
+/// Wrapper around pnet's Icmpv6Packet for adding additional funcitonality
+///
+/// A wrapper created from a byte slice remembers it, so cloning the wrapper doesn't copy the packet
+pub struct Icmpv6Packet<'a>(pnet_Icmpv6Packet<'a>, Option<&'a [u8]>);
+
+impl<'a> From<pnet_Icmpv6Packet<'a>> for Icmpv6Packet<'a> {
+    fn from(icmpv6_packet: pnet_Icmpv6Packet<'a>) -> Self {
+        Icmpv6Packet(icmpv6_packet, None)
+    }
+}
+
+impl<'a> Deref for Icmpv6Packet<'a> {
+    type Target = pnet_Icmpv6Packet<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Clone for Icmpv6Packet<'_> {
+    fn clone(&self) -> Self {
+        match self.1 {
+            Some(packet) => Icmpv6Packet::borrowed(packet),
+            None => self.create_clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Icmpv6Packet<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Icmpv6Packet").field(&self.0).finish()
+    }
+}
+
+impl<'a> Icmpv6Packet<'a> {
+    /// Wrap bytes already known to hold the header, without copying them
+    pub(crate) fn borrowed(packet: &'a [u8]) -> Icmpv6Packet<'a> {
+        Icmpv6Packet(pnet_Icmpv6Packet::new(packet).unwrap(), Some(packet))
+    }
+}
+
+impl Icmpv6Packet<'_> {
+    pub fn new<'a>(packet: &'a [u8]) -> Option<Icmpv6Packet<'a>> {
+        Icmpv6Packet::try_new(packet).ok()
+    }
+
+    /// Create an Icmpv6Packet after checking the message holds the whole header
+    pub fn try_new(packet: &[u8]) -> Result<Icmpv6Packet<'_>, Malformation> {
+        validate_icmp(packet)?;
+        Ok(Icmpv6Packet::borrowed(packet))
+    }
+
+    pub fn create_clone<'a>(&self) -> Icmpv6Packet<'a> {
+        Icmpv6Packet::from(pnet_Icmpv6Packet::owned(self.packet().to_vec()).unwrap())
+    }
+
+    pub fn is_echo_request(&self) -> bool {
+        self.get_icmpv6_type().0 == 128
+    }
+
+    pub fn is_echo_reply(&self) -> bool {
+        self.get_icmpv6_type().0 == 129
+    }
+
+    /// Get the identifier and sequence number of an echo request or reply
+    pub fn echo(&self) -> Option<Echo> {
+        if !(self.is_echo_request() || self.is_echo_reply()) {
+            return None;
+        }
+        echo(self.packet())
+    }
+
+    /// Return true if the message reports an error with a packet, such as destination unreachable or packet too big
+    pub fn is_error(&self) -> bool {
+        self.get_icmpv6_type().0 < 128
+    }
+
+    /// Return true if the message is a router or neighbor solicitation or advertisement, or a redirect
+    pub fn is_neighbor_discovery(&self) -> bool {
+        (133..=137).contains(&self.get_icmpv6_type().0)
+    }
+
+    /// Get the address a neighbor solicitation or advertisement is about, or a redirect points at
+    pub fn target_address(&self) -> Option<Ipv6Addr> {
+        if !(135..=137).contains(&self.get_icmpv6_type().0) {
+            return None;
+        }
+        let target: [u8; 16] = self.packet().get(8..24)?.try_into().unwrap();
+        Some(Ipv6Addr::from(target))
+    }
+
+    /// Decode the addresses, protocol and ports of the packet an error message was sent in response to
+    pub fn embedded_packet(&self) -> Option<EmbeddedPacket> {
+        if !self.is_error() {
+            return None;
+        }
+        embedded_ipv6(self.packet().get(8..)?)
+    }
+
+    /// Check the checksum against the addresses of the IPv6 packet carrying the message
+    pub fn checksum_status(&self, source: Ipv6Addr, destination: Ipv6Addr) -> ChecksumStatus {
+        transport_status(
+            self.packet(),
+            IpNextHeaderProtocols::Icmpv6,
+            PseudoHeader::V6(source, destination),
+            false,
+        )
+        .unwrap_or(ChecksumStatus::Invalid)
+    }
+}
+
+/// Read the identifier and sequence number from an echo message, if it is long enough to hold them
+fn echo(message: &[u8]) -> Option<Echo> {
+    let fields = message.get(4..8)?;
+    Some(Echo {
+        identifier: u16::from_be_bytes([fields[0], fields[1]]),
+        sequence: u16::from_be_bytes([fields[2], fields[3]]),
+    })
+}
+
+/// Read the ports at the start of a quoted transport header, if the protocol has them
+fn embedded_ports(protocol: IpNextHeaderProtocol, transport: &[u8]) -> (Option<u16>, Option<u16>) {
+    match (protocol, transport.get(..4)) {
+        (
+            IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp | IpNextHeaderProtocols::Sctp,
+            Some(ports),
+        ) => (
+            Some(u16::from_be_bytes([ports[0], ports[1]])),
+            Some(u16::from_be_bytes([ports[2], ports[3]])),
+        ),
+        _ => (None, None),
+    }
+}
+
+/// Decode the ipv4 header and ports quoted by an ICMP error, which may be cut short
+fn embedded_ipv4(quoted: &[u8]) -> Option<EmbeddedPacket> {
+    let header_length = usize::from(quoted.first()? & 0x0f) * 4;
+    if quoted[0] >> 4 != 4 || header_length < 20 || quoted.len() < header_length {
+        return None;
+    }
+    let protocol = IpNextHeaderProtocol(quoted[9]);
+    let flags_and_offset = u16::from_be_bytes([quoted[6], quoted[7]]);
+    let trailing_fragment = flags_and_offset & 0x1fff != 0;
+    let (source_port, destination_port) = if trailing_fragment {
+        (None, None)
+    } else {
+        embedded_ports(protocol, &quoted[header_length..])
+    };
+    Some(EmbeddedPacket {
+        source: IpAddr::V4(Ipv4Addr::new(
+            quoted[12], quoted[13], quoted[14], quoted[15],
+        )),
+        destination: IpAddr::V4(Ipv4Addr::new(
+            quoted[16], quoted[17], quoted[18], quoted[19],
+        )),
+        protocol,
+        source_port,
+        destination_port,
+        fragmented: flags_and_offset & 0x3fff != 0,
+    })
+}
+
+/// Decode the ipv6 header and ports quoted by an ICMPv6 error, which may be cut short
+///
+/// Extension headers are skipped as far as the quote goes
+fn embedded_ipv6(quoted: &[u8]) -> Option<EmbeddedPacket> {
+    if quoted.len() < 40 || quoted[0] >> 4 != 6 {
+        return None;
+    }
+    let source: [u8; 16] = quoted[8..24].try_into().unwrap();
+    let destination: [u8; 16] = quoted[24..40].try_into().unwrap();
+    let mut protocol = IpNextHeaderProtocol(quoted[6]);
+    let mut rest = &quoted[40..];
+    let (mut trailing_fragment, mut fragmented) = (false, false);
+    loop {
+        let extension_length = match protocol {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts => {
+                rest.get(1).map(|length| (usize::from(*length) + 1) * 8)
+            }
+            IpNextHeaderProtocols::Ah => rest.get(1).map(|length| (usize::from(*length) + 2) * 4),
+            IpNextHeaderProtocols::Ipv6Frag => Some(8),
+            _ => break,
+        };
+        let Some(extension) = extension_length.and_then(|length| rest.get(..length)) else {
+            break;
+        };
+        if protocol == IpNextHeaderProtocols::Ipv6Frag {
+            let fragment = u16::from_be_bytes([extension[2], extension[3]]);
+            trailing_fragment = fragment >> 3 != 0;
+            // Atomic fragments, with neither an offset nor more fragments to follow, hold the whole packet
+            fragmented = fragment & 0xfff9 != 0;
+        }
+        protocol = IpNextHeaderProtocol(extension[0]);
+        rest = &rest[extension.len()..];
+    }
+    let (source_port, destination_port) = if trailing_fragment {
+        (None, None)
+    } else {
+        embedded_ports(protocol, rest)
+    };
+    Some(EmbeddedPacket {
+        source: IpAddr::V6(Ipv6Addr::from(source)),
+        destination: IpAddr::V6(Ipv6Addr::from(destination)),
+        protocol,
+        source_port,
+        destination_port,
+        fragmented,
+    })
+}
+
+/// Collection of IcmpPacket with the shared PacketCollection functionality
+pub type IcmpPacketCollection<'a> = PacketCollection<IcmpPacket<'a>>;
+
+impl<'a> IcmpPacketCollection<'a> {
+    /// Get a collection of IcmpPacket reporting errors
+    ///
+    /// Returns a new IcmpPacketCollection containing only the error messages, whose embedded packets can be decoded
+    pub fn filter_errors(&'a self) -> IcmpPacketCollection<'a> {
+        self.par_filter(|p| p.is_error())
+    }
+}
+
+/// Collection of Icmpv6Packet with the shared PacketCollection functionality
+pub type Icmpv6PacketCollection<'a> = PacketCollection<Icmpv6Packet<'a>>;
+
+impl<'a> Icmpv6PacketCollection<'a> {
+    /// Get a collection of Icmpv6Packet reporting errors
+    ///
+    /// Returns a new Icmpv6PacketCollection containing only the error messages, whose embedded packets can be decoded
+    pub fn filter_errors(&'a self) -> Icmpv6PacketCollection<'a> {
+        self.par_filter(|p| p.is_error())
+    }
+
+    /// Get a collection of Icmpv6Packet used for neighbor discovery
+    pub fn filter_neighbor_discovery(&'a self) -> Icmpv6PacketCollection<'a> {
+        self.par_filter(|p| p.is_neighbor_discovery())
+    }
+}
@@ -0,0 +1,178 @@
+use crate::source::{FileSource, MemorySource, PacketSource, ReplaySource, ReplaySpeed};
+use crate::statistics::SendStatistics;
+use crate::EthernetFrameCollection;
+use pnet::datalink::Channel::Ethernet;
+use pnet::datalink::{self, DataLinkSender, NetworkInterface};
+use pnet::packet::Packet;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest a frame may take up under a rate limit, so tiny rates still give a time the next frame can be sent at
+const MAX_SEND_INTERVAL: Duration = Duration::from_secs(u32::MAX as u64);
+
+/// Highest rate a PacketInjector sends replayed packets at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimit {
+    PacketsPerSecond(f64),
+    BitsPerSecond(f64),
+}
+
+/// Sends ethernet frames out of a network interface, one at a time or by replaying a capture like tcpreplay
+pub struct PacketInjector {
+    interface: NetworkInterface,
+    tx: Box<dyn DataLinkSender>,
+    speed: ReplaySpeed,
+    rate_limit: Option<RateLimit>,
+    loops: usize,
+}
+
+impl fmt::Debug for PacketInjector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketInjector")
+            .field("interface", &self.interface.name)
+            .field("speed", &self.speed)
+            .field("rate_limit", &self.rate_limit)
+            .field("loops", &self.loops)
+            .finish()
+    }
+}
+
+impl PacketInjector {
+    /// Create a PacketInjector on an interface
+    pub fn new(interface: NetworkInterface) -> io::Result<PacketInjector> {
+        let tx = match datalink::channel(&interface, Default::default())? {
+            Ethernet(tx, _) => tx,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Non-ethernet channel created",
+                ))
+            }
+        };
+        Ok(PacketInjector {
+            interface,
+            tx,
+            speed: ReplaySpeed::default(),
+            rate_limit: None,
+            loops: 1,
+        })
+    }
+
+    /// Create a PacketInjector
+    ///
+    /// Takes an interface name
+    pub fn new_from_interface(interface_name: &str) -> Result<PacketInjector, Box<dyn Error>> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == interface_name)
+            .ok_or(format!("Could not find interface '{interface_name}'"))?;
+
+        Ok(PacketInjector::new(interface)?)
+    }
+
+    /// Choose how closely replays follow the gaps between the packets' timestamps
+    ///
+    /// Panics if a `ReplaySpeed::Scaled` factor isn't a positive number
+    pub fn set_speed(&mut self, speed: ReplaySpeed) {
+        if let ReplaySpeed::Scaled(factor) = speed {
+            assert!(
+                factor.is_finite() && factor > 0.0,
+                "Replay speed factor must be positive, got {factor}"
+            );
+        }
+        self.speed = speed;
+    }
+
+    /// Never send replayed packets faster than a rate, on top of the timing chosen with `set_speed`
+    ///
+    /// Each frame waits for the time the previous one takes up at that rate, so packets following a long gap don't burst
+    /// out to make up for it. Frames the interface refuses take up their time too.
+    /// Panics if the rate isn't positive
+    pub fn set_rate_limit(&mut self, rate_limit: RateLimit) {
+        let (RateLimit::PacketsPerSecond(rate) | RateLimit::BitsPerSecond(rate)) = rate_limit;
+        assert!(
+            rate.is_finite() && rate > 0.0,
+            "Rate limit must be positive, got {rate}"
+        );
+        self.rate_limit = Some(rate_limit);
+    }
+
+    /// Go through the packets of each replay `loops` times, at least once
+    pub fn set_loops(&mut self, loops: usize) {
+        self.loops = loops.max(1);
+    }
+
+    /// Send a single raw ethernet frame
+    pub fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx
+            .send_to(frame, None)
+            .unwrap_or_else(|| Err(io::Error::other("Could not send frame")))
+    }
+
+    /// Send the packets of a source, following the speed, rate limit and loops that are set
+    ///
+    /// Frames the interface refuses are counted as failures and skipped, while errors reading the source are returned
+    pub fn replay(&mut self, source: impl PacketSource + 'static) -> io::Result<SendStatistics> {
+        let source = ReplaySource::new(source, self.speed)?;
+        let started_at = Instant::now();
+        let mut allowed_at = started_at;
+        let mut statistics = SendStatistics::default();
+        for _ in 0..self.loops {
+            let mut reader = source.open()?;
+            loop {
+                let packet = match reader.next_packet() {
+                    Ok(Some((_, packet))) => packet,
+                    Ok(None) => break,
+                    // Waiting through a long gap between packets
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(e) => return Err(e),
+                };
+                thread::sleep(allowed_at.saturating_duration_since(Instant::now()));
+                allowed_at = allowed_at.max(Instant::now()) + self.send_interval(packet.len());
+                match self.send(packet) {
+                    Ok(()) => {
+                        statistics.packets_sent += 1;
+                        statistics.bytes_sent += packet.len() as u64;
+                    }
+                    Err(_) => statistics.send_failures += 1,
+                }
+            }
+        }
+        statistics.duration = started_at.elapsed();
+        Ok(statistics)
+    }
+
+    /// Send the packets of a pcap or pcapng file
+    pub fn replay_file(&mut self, path: impl AsRef<Path>) -> io::Result<SendStatistics> {
+        self.replay(FileSource::new(path)?)
+    }
+
+    /// Send a collection of frames
+    ///
+    /// Frames carry no timestamps, so they go out back to back unless a rate limit is set
+    pub fn replay_frames(
+        &mut self,
+        frames: &EthernetFrameCollection,
+    ) -> io::Result<SendStatistics> {
+        let packets = frames.iter().map(|frame| frame.packet().to_vec()).collect();
+        self.replay(MemorySource::new(packets))
+    }
+
+    /// Time a frame of `length` bytes takes up under the rate limit, before the next one may be sent
+    ///
+    /// Capped at `MAX_SEND_INTERVAL` for rates so low the time wouldn't fit
+    fn send_interval(&self, length: usize) -> Duration {
+        let seconds = match self.rate_limit {
+            None => return Duration::ZERO,
+            Some(RateLimit::PacketsPerSecond(rate)) => 1.0 / rate,
+            Some(RateLimit::BitsPerSecond(rate)) => length as f64 * 8.0 / rate,
+        };
+        Duration::try_from_secs_f64(seconds)
+            .unwrap_or(MAX_SEND_INTERVAL)
+            .min(MAX_SEND_INTERVAL)
+    }
+}
@@ -1,16 +1,24 @@
-use pnet::packet::ipv4::Ipv4Packet as pnet_Ipv4Packet;
+use crate::checksum::{
+    ipv4_header_status, transport_status, ChecksumOffload, ChecksumStatus, PseudoHeader,
+};
+use crate::defrag::{Defragmentation, Defragmenter, Reassembled};
+use crate::dissection::{validate_ipv4, Malformation};
+use crate::tunnel::{self, Tunnel, TunnelPayload};
+use crate::PacketCollection;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet as pnet_Ipv4Packet};
 use pnet::packet::Packet;
+use std::fmt;
 use std::net::Ipv4Addr;
 use std::ops::Deref;
-use std::sync::Arc;
 
 /// Wrapper around pnet's Ipv4Packet for adding additional funcitonality
-#[derive(Debug)]
-pub struct Ipv4Packet<'a>(pnet_Ipv4Packet<'a>);
+///
+/// A wrapper created from a byte slice remembers it, so cloning the wrapper doesn't copy the packet
+pub struct Ipv4Packet<'a>(pnet_Ipv4Packet<'a>, Option<&'a [u8]>);
 
 impl<'a> From<pnet_Ipv4Packet<'a>> for Ipv4Packet<'a> {
     fn from(ipv4_packet: pnet_Ipv4Packet<'a>) -> Self {
-        Ipv4Packet(ipv4_packet)
+        Ipv4Packet(ipv4_packet, None)
     }
 }
 
@@ -22,41 +30,158 @@ impl<'a> Deref for Ipv4Packet<'a> {
     }
 }
 
+impl Clone for Ipv4Packet<'_> {
+    fn clone(&self) -> Self {
+        match self.1 {
+            Some(packet) => Ipv4Packet::borrowed(packet),
+            None => self.create_clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Ipv4Packet<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Ipv4Packet").field(&self.0).finish()
+    }
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// Wrap bytes already known to hold the header, without copying them
+    pub(crate) fn borrowed(packet: &'a [u8]) -> Ipv4Packet<'a> {
+        Ipv4Packet(pnet_Ipv4Packet::new(packet).unwrap(), Some(packet))
+    }
+
+    /// Get the bytes the wrapper borrows, if it doesn't own a copy of them
+    pub(crate) fn source(&self) -> Option<&'a [u8]> {
+        self.1
+    }
+}
+
 impl Ipv4Packet<'_> {
     pub fn new<'a>(packet: &'a [u8]) -> Option<Ipv4Packet<'a>>{
-        pnet_Ipv4Packet::new(packet).map(Ipv4Packet::from)
+        Ipv4Packet::try_new(packet).ok()
+    }
+
+    /// Create an Ipv4Packet after checking the version, IHL and total length of the header
+    pub fn try_new(packet: &[u8]) -> Result<Ipv4Packet<'_>, Malformation> {
+        validate_ipv4(packet)?;
+        Ok(Ipv4Packet::borrowed(packet))
     }
 
     pub fn create_clone<'a>(&self) -> Ipv4Packet<'a> {
         Ipv4Packet::from(pnet_Ipv4Packet::owned(self.packet().to_vec()).unwrap())
     }
-}
 
-/// Wrapper around an Arc<[Ipv4Packet]> for additional functionality
-#[derive(Debug)]
-pub struct Ipv4PacketCollection<'a>(Arc<[Ipv4Packet<'a>]>);
+    /// Check the header checksum
+    pub fn header_checksum_status(&self) -> ChecksumStatus {
+        ipv4_header_status(self.packet())
+    }
 
-impl<'a> FromIterator<Ipv4Packet<'a>> for Ipv4PacketCollection<'a> {
-    fn from_iter<I: IntoIterator<Item = Ipv4Packet<'a>>>(iter: I) -> Self {
-        Ipv4PacketCollection(iter.into_iter().collect())
+    /// Check the checksum of the TCP, UDP or ICMP message carried by the packet
+    ///
+    /// Returns None for other protocols, malformed messages and fragments, whose checksum covers data they don't hold
+    pub fn transport_checksum_status(&self) -> Option<ChecksumStatus> {
+        self.transport_checksum_status_with(&ChecksumOffload::Never)
     }
-}
 
-impl<'a> Deref for Ipv4PacketCollection<'a> {
-    type Target = Arc<[Ipv4Packet<'a>]>;
+    /// Check the checksum of the TCP, UDP or ICMP message carried by the packet, which `offload` may allow the sender
+    /// to have left for the network card
+    pub fn transport_checksum_status_with(
+        &self,
+        offload: &ChecksumOffload,
+    ) -> Option<ChecksumStatus> {
+        if self.get_flags() & Ipv4Flags::MoreFragments != 0 || self.get_fragment_offset() != 0 {
+            return None;
+        }
+        transport_status(
+            ipv4_payload(self.packet()),
+            self.get_next_level_protocol(),
+            PseudoHeader::V4(self.get_source(), self.get_destination()),
+            offload.allows(self.get_source().into()),
+        )
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// Return true if the header or transport checksum is invalid
+    pub fn has_bad_checksum(&self) -> bool {
+        self.has_bad_checksum_with(&ChecksumOffload::Never)
+    }
+
+    /// Return true if the header or transport checksum is invalid
+    ///
+    /// Transport checksums `offload` allows the sender to have left for the network card don't count as invalid
+    pub fn has_bad_checksum_with(&self, offload: &ChecksumOffload) -> bool {
+        self.header_checksum_status() == ChecksumStatus::Invalid
+            || self.transport_checksum_status_with(offload) == Some(ChecksumStatus::Invalid)
+    }
+
+    /// Get the tunnel (GRE, VXLAN, Geneve or IP-in-IP) carried by the packet, if any
+    pub fn tunnel(&self) -> Option<Tunnel> {
+        tunnel::decapsulate(self.packet()).map(|(tunnel, _)| tunnel)
+    }
+
+    /// Get the tunnel carried by the packet along with the header found inside it
+    pub fn decapsulate(&self) -> Option<(Tunnel, TunnelPayload<'_>)> {
+        tunnel::decapsulate(self.packet()).map(|(tunnel, inner)| (tunnel, inner.into()))
+    }
+
+    /// Get every tunnel nested in the packet, outermost first
+    pub fn tunnels(&self) -> Vec<Tunnel> {
+        tunnel::nested_tunnels(self.packet())
+    }
+
+    /// Get the innermost ipv4 packet
+    ///
+    /// Returns a view of the packet itself when it doesn't carry an ipv4 tunnel
+    pub fn innermost(&self) -> Ipv4Packet<'_> {
+        Ipv4Packet::borrowed(tunnel::innermost_ipv4(self.packet()))
     }
 }
 
+/// Get the payload of a raw ipv4 packet, bounded by its header and total lengths
+pub(crate) fn ipv4_payload(packet: &[u8]) -> &[u8] {
+    let header_length = (usize::from(packet[0] & 0x0f) * 4).min(packet.len());
+    let total_length = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    &packet[header_length..total_length.clamp(header_length, packet.len())]
+}
+
+/// Collection of Ipv4Packet with the shared PacketCollection functionality
+pub type Ipv4PacketCollection<'a> = PacketCollection<Ipv4Packet<'a>>;
+
 impl<'a> Ipv4PacketCollection<'a> {
     pub fn filter_only_host(&'a self, host: Ipv4Addr) -> Ipv4PacketCollection<'a> {
-        Ipv4PacketCollection(
-            self.iter()
-                .filter(|p| p.get_source() == host || p.get_destination() == host)
-                .map(|p| p.create_clone())
-                .collect::<Arc<[Ipv4Packet]>>(),
-        )
+        self.par_filter(|p| p.get_source() == host || p.get_destination() == host)
+    }
+
+    /// Get a collection of Ipv4Packet with a bad checksum
+    ///
+    /// Returns a new Ipv4PacketCollection containing only the packets whose header or transport checksum is invalid
+    pub fn filter_bad_checksums(&'a self) -> Ipv4PacketCollection<'a> {
+        self.par_filter(|p| p.has_bad_checksum())
+    }
+
+    /// Get a collection of Ipv4Packet with a bad checksum, not counting checksums `offload` allows to be left unfinished
+    pub fn filter_bad_checksums_with(
+        &'a self,
+        offload: &ChecksumOffload,
+    ) -> Ipv4PacketCollection<'a> {
+        self.par_filter(|p| p.has_bad_checksum_with(offload))
+    }
+
+    /// Reassemble fragmented packets, keeping the packets that weren't fragmented as they are
+    ///
+    /// The packets carry no capture time, so fragment sets never time out
+    pub fn defragment(&self, defragmenter: &Defragmenter) -> Defragmentation<Ipv4Packet<'a>> {
+        let (reassembled, reports) =
+            defragmenter.reassemble_ipv4(self.iter().map(|p| (None, p.packet())));
+        let packets = reassembled
+            .into_iter()
+            .map(|packet| match packet {
+                Reassembled::Unchanged(index) => self[index].clone(),
+                Reassembled::Rebuilt(packet) => {
+                    Ipv4Packet::from(pnet_Ipv4Packet::owned(packet).unwrap())
+                }
+            })
+            .collect();
+        Defragmentation { packets, reports }
     }
 }
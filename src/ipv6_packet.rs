@@ -0,0 +1,219 @@
+use crate::checksum::{transport_status, ChecksumOffload, ChecksumStatus, PseudoHeader};
+use crate::defrag::{Defragmentation, Defragmenter, Reassembled};
+use crate::dissection::{validate_ipv6, Malformation, ProtocolLayer};
+use crate::PacketCollection;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv6::Ipv6Packet as pnet_Ipv6Packet;
+use pnet::packet::Packet;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::ops::Deref;
+
+/// Wrapper around pnet's Ipv6Packet for adding additional funcitonality
+///
+/// A wrapper created from a byte slice remembers it, so cloning the wrapper doesn't copy the packet
+pub struct Ipv6Packet<'a>(pnet_Ipv6Packet<'a>, Option<&'a [u8]>);
+
+impl<'a> From<pnet_Ipv6Packet<'a>> for Ipv6Packet<'a> {
+    fn from(ipv6_packet: pnet_Ipv6Packet<'a>) -> Self {
+        Ipv6Packet(ipv6_packet, None)
+    }
+}
+
+impl<'a> Deref for Ipv6Packet<'a> {
+    type Target = pnet_Ipv6Packet<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Clone for Ipv6Packet<'_> {
+    fn clone(&self) -> Self {
+        match self.1 {
+            Some(packet) => Ipv6Packet::borrowed(packet),
+            None => self.create_clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Ipv6Packet<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Ipv6Packet").field(&self.0).finish()
+    }
+}
+
+impl<'a> Ipv6Packet<'a> {
+    /// Wrap bytes already known to hold the header, without copying them
+    pub(crate) fn borrowed(packet: &'a [u8]) -> Ipv6Packet<'a> {
+        Ipv6Packet(pnet_Ipv6Packet::new(packet).unwrap(), Some(packet))
+    }
+}
+
+impl Ipv6Packet<'_> {
+    pub fn new<'a>(packet: &'a [u8]) -> Option<Ipv6Packet<'a>> {
+        Ipv6Packet::try_new(packet).ok()
+    }
+
+    /// Create an Ipv6Packet after checking the version and payload length of the header
+    pub fn try_new(packet: &[u8]) -> Result<Ipv6Packet<'_>, Malformation> {
+        validate_ipv6(packet)?;
+        Ok(Ipv6Packet::borrowed(packet))
+    }
+
+    pub fn create_clone<'a>(&self) -> Ipv6Packet<'a> {
+        Ipv6Packet::from(pnet_Ipv6Packet::owned(self.packet().to_vec()).unwrap())
+    }
+
+    /// Get the protocol carried after the extension headers
+    pub fn upper_layer_protocol(&self) -> IpNextHeaderProtocol {
+        match upper_layer(self.packet()) {
+            Ok(upper_layer) => upper_layer.protocol,
+            Err(_) => self.get_next_header(),
+        }
+    }
+
+    /// Get the payload carried after the extension headers
+    ///
+    /// Returns an empty payload when the payload length runs past the packet or the extension headers are truncated
+    pub fn upper_layer_payload(&self) -> &[u8] {
+        upper_layer(self.packet())
+            .map(|upper_layer| upper_layer.payload)
+            .unwrap_or_default()
+    }
+
+    /// Check the checksum of the TCP, UDP or ICMPv6 message carried after the extension headers
+    ///
+    /// Returns None for other protocols, malformed messages and fragments, whose checksum covers data they don't hold
+    pub fn transport_checksum_status(&self) -> Option<ChecksumStatus> {
+        self.transport_checksum_status_with(&ChecksumOffload::Never)
+    }
+
+    /// Check the checksum of the TCP, UDP or ICMPv6 message carried after the extension headers, which `offload` may
+    /// allow the sender to have left for the network card
+    pub fn transport_checksum_status_with(
+        &self,
+        offload: &ChecksumOffload,
+    ) -> Option<ChecksumStatus> {
+        validate_ipv6(self.packet()).ok()?;
+        let upper_layer = upper_layer(self.packet())
+            .ok()
+            .filter(|upper_layer| !upper_layer.fragmented)?;
+        transport_status(
+            upper_layer.payload,
+            upper_layer.protocol,
+            PseudoHeader::V6(self.get_source(), self.get_destination()),
+            offload.allows(self.get_source().into()),
+        )
+    }
+
+    /// Return true if the transport checksum is invalid
+    pub fn has_bad_checksum(&self) -> bool {
+        self.has_bad_checksum_with(&ChecksumOffload::Never)
+    }
+
+    /// Return true if the transport checksum is invalid
+    ///
+    /// Checksums `offload` allows the sender to have left for the network card don't count as invalid
+    pub fn has_bad_checksum_with(&self, offload: &ChecksumOffload) -> bool {
+        self.transport_checksum_status_with(offload) == Some(ChecksumStatus::Invalid)
+    }
+}
+
+/// Protocol and payload found after the extension headers of an ipv6 packet
+pub(crate) struct UpperLayer<'a> {
+    pub(crate) protocol: IpNextHeaderProtocol,
+    pub(crate) payload: &'a [u8],
+    /// The packet is a fragment other than the first, so the payload doesn't start with a header
+    pub(crate) trailing_fragment: bool,
+    /// The packet is a fragment, so the payload may not hold the whole upper layer message
+    pub(crate) fragmented: bool,
+}
+
+/// Walk the extension headers of a raw ipv6 packet holding at least the fixed header
+pub(crate) fn upper_layer(packet: &[u8]) -> Result<UpperLayer<'_>, Malformation> {
+    let header_length = pnet_Ipv6Packet::minimum_packet_size();
+    let payload_length = u16::from_be_bytes([packet[4], packet[5]]);
+    let payload = packet
+        .get(header_length..header_length + usize::from(payload_length))
+        .ok_or(Malformation::Ipv6PayloadLength(payload_length))?;
+    let mut upper_layer = UpperLayer {
+        protocol: IpNextHeaderProtocol(packet[6]),
+        payload,
+        trailing_fragment: false,
+        fragmented: false,
+    };
+    loop {
+        let extension_length = match upper_layer.protocol {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts => upper_layer
+                .payload
+                .get(1)
+                .map(|length| (usize::from(*length) + 1) * 8),
+            IpNextHeaderProtocols::Ah => upper_layer
+                .payload
+                .get(1)
+                .map(|length| (usize::from(*length) + 2) * 4),
+            IpNextHeaderProtocols::Ipv6Frag => Some(8),
+            _ => return Ok(upper_layer),
+        };
+        let extension = extension_length
+            .and_then(|length| upper_layer.payload.get(..length))
+            .ok_or(Malformation::Truncated {
+                layer: ProtocolLayer::Network,
+                length: upper_layer.payload.len(),
+                needed: extension_length.unwrap_or(2),
+            })?;
+        if upper_layer.protocol == IpNextHeaderProtocols::Ipv6Frag {
+            let fragment = u16::from_be_bytes([extension[2], extension[3]]);
+            upper_layer.trailing_fragment |= fragment >> 3 != 0;
+            // Atomic fragments, with neither an offset nor more fragments to follow, hold the whole message
+            upper_layer.fragmented |= fragment & 0xfff9 != 0;
+        }
+        upper_layer.protocol = IpNextHeaderProtocol(extension[0]);
+        upper_layer.payload = &upper_layer.payload[extension.len()..];
+    }
+}
+
+/// Collection of Ipv6Packet with the shared PacketCollection functionality
+pub type Ipv6PacketCollection<'a> = PacketCollection<Ipv6Packet<'a>>;
+
+impl<'a> Ipv6PacketCollection<'a> {
+    pub fn filter_only_host(&'a self, host: Ipv6Addr) -> Ipv6PacketCollection<'a> {
+        self.par_filter(|p| p.get_source() == host || p.get_destination() == host)
+    }
+
+    /// Get a collection of Ipv6Packet with a bad checksum
+    ///
+    /// Returns a new Ipv6PacketCollection containing only the packets whose transport checksum is invalid
+    pub fn filter_bad_checksums(&'a self) -> Ipv6PacketCollection<'a> {
+        self.par_filter(|p| p.has_bad_checksum())
+    }
+
+    /// Get a collection of Ipv6Packet with a bad checksum, not counting checksums `offload` allows to be left unfinished
+    pub fn filter_bad_checksums_with(
+        &'a self,
+        offload: &ChecksumOffload,
+    ) -> Ipv6PacketCollection<'a> {
+        self.par_filter(|p| p.has_bad_checksum_with(offload))
+    }
+
+    /// Reassemble packets carrying a fragment header, keeping the other packets as they are
+    ///
+    /// The packets carry no capture time, so fragment sets never time out
+    pub fn defragment(&self, defragmenter: &Defragmenter) -> Defragmentation<Ipv6Packet<'a>> {
+        let (reassembled, reports) =
+            defragmenter.reassemble_ipv6(self.iter().map(|p| (None, p.packet())));
+        let packets = reassembled
+            .into_iter()
+            .map(|packet| match packet {
+                Reassembled::Unchanged(index) => self[index].clone(),
+                Reassembled::Rebuilt(packet) => {
+                    Ipv6Packet::from(pnet_Ipv6Packet::owned(packet).unwrap())
+                }
+            })
+            .collect();
+        Defragmentation { packets, reports }
+    }
+}
@@ -0,0 +1,493 @@
+use crate::checksum::ChecksumOffload;
+use crate::dissection::{Malformation, ProtocolLayer};
+use crate::ethernet_frame::strip_frame_tags;
+use crate::ipv4_packet::ipv4_payload;
+use crate::ipv6_packet::upper_layer;
+use crate::tunnel::{self, InnerBytes, MAX_TUNNEL_DEPTH};
+use crate::udp_datagram::udp_payload;
+use crate::{
+    Echo, EchoPair, EthernetFrame, IcmpPacket, Icmpv6Packet, Ipv4Packet, Ipv6Packet, MplsLabel,
+    PacketCollection, TcpSegment, Tunnel, UdpDatagram, VlanTag,
+};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket as pnet_EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Flags;
+use pnet::packet::Packet;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::IpAddr;
+
+/// A single layer recognized in a packet
+#[derive(Clone, Debug)]
+pub enum Layer<'a> {
+    Ethernet(EthernetFrame<'a>),
+    Vlan(VlanTag),
+    Mpls(MplsLabel),
+    Ipv4(Ipv4Packet<'a>),
+    Ipv6(Ipv6Packet<'a>),
+    Tunnel(Tunnel),
+    Icmp(IcmpPacket<'a>),
+    Icmpv6(Icmpv6Packet<'a>),
+    Tcp(TcpSegment<'a>),
+    Udp(UdpDatagram<'a>),
+    /// Bytes carried by the innermost transport layer
+    Application(&'a [u8]),
+}
+
+impl Layer<'_> {
+    /// Get the layer of the network stack this layer belongs to
+    pub fn protocol_layer(&self) -> ProtocolLayer {
+        match self {
+            Layer::Ethernet(_) | Layer::Vlan(_) | Layer::Mpls(_) => ProtocolLayer::Link,
+            Layer::Ipv4(_)
+            | Layer::Ipv6(_)
+            | Layer::Tunnel(_)
+            | Layer::Icmp(_)
+            | Layer::Icmpv6(_) => ProtocolLayer::Network,
+            Layer::Tcp(_) | Layer::Udp(_) => ProtocolLayer::Transport,
+            Layer::Application(_) => ProtocolLayer::Application,
+        }
+    }
+}
+
+/// A packet decoded into every layer wiretap recognizes, outermost first
+///
+/// Tunneled traffic holds the layers of the outer packet followed by the layers of the inner packet
+#[derive(Clone, Debug)]
+pub struct LayeredPacket<'a> {
+    layers: Vec<Layer<'a>>,
+    malformation: Option<Malformation>,
+}
+
+impl LayeredPacket<'_> {
+    /// Decode a raw ethernet frame
+    ///
+    /// Decoding stops at the first layer that isn't recognized or is malformed
+    pub fn new(packet: &[u8]) -> LayeredPacket<'_> {
+        let mut layered_packet = LayeredPacket {
+            layers: vec![],
+            malformation: None,
+        };
+        layered_packet.malformation = layered_packet.dissect_ethernet(packet).err();
+        layered_packet
+    }
+
+    /// Iterate over the layers of the packet, outermost first
+    pub fn layers(&self) -> std::slice::Iter<'_, Layer<'_>> {
+        self.layers.iter()
+    }
+
+    /// Get the reason decoding stopped early, if it did
+    pub fn malformation(&self) -> Option<Malformation> {
+        self.malformation
+    }
+
+    /// Return true if decoding stopped on a malformed layer
+    pub fn is_malformed(&self) -> bool {
+        self.malformation.is_some()
+    }
+
+    /// Get the outermost ethernet frame
+    pub fn ethernet(&self) -> Option<&EthernetFrame<'_>> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Ethernet(ethernet_frame) => Some(ethernet_frame),
+            _ => None,
+        })
+    }
+
+    /// Get every VLAN tag of the packet, outermost first
+    pub fn vlan_tags(&self) -> Vec<VlanTag> {
+        self.layers
+            .iter()
+            .filter_map(|layer| match layer {
+                Layer::Vlan(vlan_tag) => Some(*vlan_tag),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Get every MPLS label of the packet, top of the stack first
+    pub fn mpls_labels(&self) -> Vec<MplsLabel> {
+        self.layers
+            .iter()
+            .filter_map(|layer| match layer {
+                Layer::Mpls(mpls_label) => Some(*mpls_label),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Get the outermost ipv4 packet
+    pub fn ipv4(&self) -> Option<&Ipv4Packet<'_>> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Ipv4(ipv4_packet) => Some(ipv4_packet),
+            _ => None,
+        })
+    }
+
+    /// Get the outermost ipv6 packet
+    pub fn ipv6(&self) -> Option<&Ipv6Packet<'_>> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Ipv6(ipv6_packet) => Some(ipv6_packet),
+            _ => None,
+        })
+    }
+
+    /// Get every tunnel of the packet, outermost first
+    pub fn tunnels(&self) -> Vec<Tunnel> {
+        self.layers
+            .iter()
+            .filter_map(|layer| match layer {
+                Layer::Tunnel(tunnel) => Some(*tunnel),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Get the outermost icmp message of the packet
+    pub fn icmp(&self) -> Option<&IcmpPacket<'_>> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Icmp(icmp_packet) => Some(icmp_packet),
+            _ => None,
+        })
+    }
+
+    /// Get the outermost icmpv6 message of the packet
+    pub fn icmpv6(&self) -> Option<&Icmpv6Packet<'_>> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Icmpv6(icmpv6_packet) => Some(icmpv6_packet),
+            _ => None,
+        })
+    }
+
+    /// Get the tcp segment of the packet
+    pub fn tcp(&self) -> Option<&TcpSegment<'_>> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Tcp(tcp_segment) => Some(tcp_segment),
+            _ => None,
+        })
+    }
+
+    /// Get the outermost udp datagram of the packet
+    pub fn udp(&self) -> Option<&UdpDatagram<'_>> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Udp(udp_datagram) => Some(udp_datagram),
+            _ => None,
+        })
+    }
+
+    /// Get the bytes carried by the innermost transport layer
+    pub fn application(&self) -> Option<&[u8]> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Application(payload) => Some(*payload),
+            _ => None,
+        })
+    }
+
+    /// Return true if any IP packet in the packet, including those carried by tunnels, has an invalid checksum
+    pub fn has_bad_checksum(&self) -> bool {
+        self.has_bad_checksum_with(&ChecksumOffload::Never)
+    }
+
+    /// Return true if any IP packet in the packet has an invalid checksum
+    ///
+    /// Transport checksums `offload` allows the sender to have left for the network card don't count as invalid
+    pub fn has_bad_checksum_with(&self, offload: &ChecksumOffload) -> bool {
+        self.layers.iter().any(|layer| match layer {
+            Layer::Ipv4(ipv4_packet) => ipv4_packet.has_bad_checksum_with(offload),
+            Layer::Ipv6(ipv6_packet) => ipv6_packet.has_bad_checksum_with(offload),
+            _ => false,
+        })
+    }
+
+    /// Hash the innermost flow of the packet, so that both directions of a connection hash the same
+    ///
+    /// Covers the addresses, protocol and ports of ip traffic, and the ethernet addresses of anything else.
+    /// Fragments leave the ports out, since only the first fragment of a packet carries them
+    pub fn flow_hash(&self) -> u64 {
+        let mut flow: Option<(IpAddr, IpAddr, u8, u16, u16)> = None;
+        let mut fragmented = false;
+        for layer in self.layers.iter() {
+            match layer {
+                Layer::Ipv4(ipv4_packet) => {
+                    fragmented = ipv4_packet.get_flags() & Ipv4Flags::MoreFragments != 0
+                        || ipv4_packet.get_fragment_offset() != 0;
+                    flow = Some((
+                        ipv4_packet.get_source().into(),
+                        ipv4_packet.get_destination().into(),
+                        ipv4_packet.get_next_level_protocol().0,
+                        0,
+                        0,
+                    ))
+                }
+                Layer::Ipv6(ipv6_packet) => {
+                    fragmented = upper_layer(ipv6_packet.packet())
+                        .is_ok_and(|upper_layer| upper_layer.fragmented);
+                    flow = Some((
+                        ipv6_packet.get_source().into(),
+                        ipv6_packet.get_destination().into(),
+                        ipv6_packet.upper_layer_protocol().0,
+                        0,
+                        0,
+                    ))
+                }
+                Layer::Tcp(tcp_segment) => {
+                    if let Some(flow) = flow.as_mut().filter(|_| !fragmented) {
+                        flow.3 = tcp_segment.get_source();
+                        flow.4 = tcp_segment.get_destination();
+                    }
+                }
+                Layer::Udp(udp_datagram) => {
+                    if let Some(flow) = flow.as_mut().filter(|_| !fragmented) {
+                        flow.3 = udp_datagram.get_source();
+                        flow.4 = udp_datagram.get_destination();
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match (flow, self.ethernet()) {
+            (Some((source, destination, protocol, source_port, destination_port)), _) => {
+                hash_flow(source, destination, protocol, source_port, destination_port)
+            }
+            (None, Some(ethernet_frame)) => {
+                let mut hasher = DefaultHasher::new();
+                let addresses = [
+                    ethernet_frame.get_source(),
+                    ethernet_frame.get_destination(),
+                ];
+                (addresses.iter().min(), addresses.iter().max()).hash(&mut hasher);
+                hasher.finish()
+            }
+            (None, None) => DefaultHasher::new().finish(),
+        }
+    }
+}
+
+/// Hash a flow so that both directions hash the same
+pub(crate) fn hash_flow(
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: u8,
+    source_port: u16,
+    destination_port: u16,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let endpoints = [(source, source_port), (destination, destination_port)];
+    (endpoints.iter().min(), endpoints.iter().max(), protocol).hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<'a> LayeredPacket<'a> {
+    fn tunnel_depth(&self) -> usize {
+        self.layers
+            .iter()
+            .filter(|layer| matches!(layer, Layer::Tunnel(_)))
+            .count()
+    }
+
+    fn dissect_ethernet(&mut self, frame: &'a [u8]) -> Result<(), Malformation> {
+        let needed = pnet_EthernetPacket::minimum_packet_size();
+        if frame.len() < needed {
+            return Err(Malformation::Truncated {
+                layer: ProtocolLayer::Link,
+                length: frame.len(),
+                needed,
+            });
+        }
+        self.layers
+            .push(Layer::Ethernet(EthernetFrame::borrowed(frame)));
+        let stripped = strip_frame_tags(frame);
+        self.layers
+            .extend(stripped.vlan_tags.into_iter().map(Layer::Vlan));
+        self.layers
+            .extend(stripped.mpls_labels.into_iter().map(Layer::Mpls));
+        match stripped.ethertype {
+            EtherTypes::Ipv4 => self.dissect_ipv4(stripped.payload),
+            EtherTypes::Ipv6 => self.dissect_ipv6(stripped.payload),
+            _ => Ok(()),
+        }
+    }
+
+    fn dissect_ipv4(&mut self, packet: &'a [u8]) -> Result<(), Malformation> {
+        let ipv4_packet = Ipv4Packet::try_new(packet)?;
+        let protocol = ipv4_packet.get_next_level_protocol();
+        let trailing_fragment = ipv4_packet.get_fragment_offset() != 0;
+        self.layers.push(Layer::Ipv4(ipv4_packet));
+        if trailing_fragment {
+            return Ok(());
+        }
+        match protocol {
+            IpNextHeaderProtocols::Gre | IpNextHeaderProtocols::Ipv4 => {
+                match tunnel::decapsulate(packet) {
+                    Some((tunnel, inner)) => self.dissect_tunnel(tunnel, inner),
+                    None => Ok(()),
+                }
+            }
+            _ => self.dissect_transport(protocol, ipv4_payload(packet)),
+        }
+    }
+
+    fn dissect_ipv6(&mut self, packet: &'a [u8]) -> Result<(), Malformation> {
+        self.layers.push(Layer::Ipv6(Ipv6Packet::try_new(packet)?));
+        let upper_layer = upper_layer(packet)?;
+        if upper_layer.trailing_fragment {
+            return Ok(());
+        }
+        self.dissect_transport(upper_layer.protocol, upper_layer.payload)
+    }
+
+    fn dissect_transport(
+        &mut self,
+        protocol: IpNextHeaderProtocol,
+        payload: &'a [u8],
+    ) -> Result<(), Malformation> {
+        match protocol {
+            IpNextHeaderProtocols::Tcp => {
+                let tcp_segment = TcpSegment::try_new(payload)?;
+                let header_length = usize::from(tcp_segment.get_data_offset()) * 4;
+                self.layers.push(Layer::Tcp(tcp_segment));
+                self.push_application(&payload[header_length..]);
+                Ok(())
+            }
+            IpNextHeaderProtocols::Icmp => {
+                self.layers.push(Layer::Icmp(IcmpPacket::try_new(payload)?));
+                Ok(())
+            }
+            IpNextHeaderProtocols::Icmpv6 => {
+                self.layers
+                    .push(Layer::Icmpv6(Icmpv6Packet::try_new(payload)?));
+                Ok(())
+            }
+            IpNextHeaderProtocols::Udp => {
+                self.layers.push(Layer::Udp(UdpDatagram::try_new(payload)?));
+                match tunnel::decapsulate_udp(payload) {
+                    Some((tunnel, inner)) => self.dissect_tunnel(tunnel, inner),
+                    None => {
+                        self.push_application(udp_payload(payload));
+                        Ok(())
+                    }
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn dissect_tunnel(
+        &mut self,
+        tunnel: Tunnel,
+        inner: InnerBytes<'a>,
+    ) -> Result<(), Malformation> {
+        if self.tunnel_depth() >= MAX_TUNNEL_DEPTH {
+            return Ok(());
+        }
+        self.layers.push(Layer::Tunnel(tunnel));
+        match inner {
+            InnerBytes::Ethernet(frame) => self.dissect_ethernet(frame),
+            InnerBytes::Ipv4(packet) => self.dissect_ipv4(packet),
+            InnerBytes::Ipv6(packet) => self.dissect_ipv6(packet),
+            InnerBytes::Other(payload) => {
+                self.push_application(payload);
+                Ok(())
+            }
+        }
+    }
+
+    fn push_application(&mut self, payload: &'a [u8]) {
+        if !payload.is_empty() {
+            self.layers.push(Layer::Application(payload));
+        }
+    }
+
+    /// Get the addresses of the IP packet carrying an echo request or reply, its identifier and sequence number, and
+    /// whether it is a reply
+    fn echo(&self) -> Option<(IpAddr, IpAddr, Echo, bool)> {
+        let mut addresses: Option<(IpAddr, IpAddr)> = None;
+        for layer in self.layers.iter() {
+            match layer {
+                Layer::Ipv4(ipv4_packet) => {
+                    addresses = Some((
+                        ipv4_packet.get_source().into(),
+                        ipv4_packet.get_destination().into(),
+                    ))
+                }
+                Layer::Ipv6(ipv6_packet) => {
+                    addresses = Some((
+                        ipv6_packet.get_source().into(),
+                        ipv6_packet.get_destination().into(),
+                    ))
+                }
+                Layer::Icmp(icmp_packet) => {
+                    let (source, destination) = addresses?;
+                    return icmp_packet
+                        .echo()
+                        .map(|echo| (source, destination, echo, icmp_packet.is_echo_reply()));
+                }
+                Layer::Icmpv6(icmpv6_packet) => {
+                    let (source, destination) = addresses?;
+                    return icmpv6_packet
+                        .echo()
+                        .map(|echo| (source, destination, echo, icmpv6_packet.is_echo_reply()));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// Collection of LayeredPacket with the shared PacketCollection functionality
+pub type LayeredPacketCollection<'a> = PacketCollection<LayeredPacket<'a>>;
+
+impl<'a> LayeredPacketCollection<'a> {
+    /// Get a collection of LayeredPacket with a bad checksum
+    ///
+    /// Returns a new LayeredPacketCollection containing only the packets with an invalid checksum at any layer
+    pub fn filter_bad_checksums(&self) -> LayeredPacketCollection<'a> {
+        self.par_filter(|p| p.has_bad_checksum())
+    }
+
+    /// Get a collection of LayeredPacket with a bad checksum, not counting checksums `offload` allows to be left
+    /// unfinished
+    pub fn filter_bad_checksums_with(
+        &self,
+        offload: &ChecksumOffload,
+    ) -> LayeredPacketCollection<'a> {
+        self.par_filter(|p| p.has_bad_checksum_with(offload))
+    }
+
+    /// Pair ICMP and ICMPv6 echo requests with their replies, in the order the requests were sent
+    ///
+    /// A reply answers the earliest unanswered request with the same identifier and sequence number that was sent from
+    /// the reply's destination to its source. Requests that went unanswered, as in a ping sweep of empty addresses,
+    /// have no reply, and replies without a request are left out
+    pub fn echo_pairs(&self) -> Vec<EchoPair<LayeredPacket<'a>>> {
+        let mut pairs: Vec<EchoPair<LayeredPacket<'a>>> = vec![];
+        let mut unanswered: HashMap<(IpAddr, IpAddr, Echo), VecDeque<usize>> = HashMap::new();
+        for packet in self.iter() {
+            match packet.echo() {
+                Some((source, destination, echo, false)) => {
+                    unanswered
+                        .entry((source, destination, echo))
+                        .or_default()
+                        .push_back(pairs.len());
+                    pairs.push(EchoPair {
+                        request: packet.clone(),
+                        reply: None,
+                    });
+                }
+                Some((source, destination, echo, true)) => {
+                    if let Some(index) = unanswered
+                        .get_mut(&(destination, source, echo))
+                        .and_then(VecDeque::pop_front)
+                    {
+                        pairs[index].reply = Some(packet.clone());
+                    }
+                }
+                None => {}
+            }
+        }
+        pairs
+    }
+}
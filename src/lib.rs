@@ -36,31 +36,24 @@
 //!
 //! ```rust,ignore
 //! use wiretap;
-//! use wiretap::Packet;
 //! use std::{thread, time};
-//! use std::ops::Deref;
-//! 
-//! 
+//!
 //! // Print the SrcIP:SrcPort --> DestIP:DestPort
-//! fn print_to_from(bytes: Vec<u8>) {
-//!     // Make sure the payload represents an EthernetFrame
-//!     if let Some(ethernet_packet) = wiretap::EthernetFrame::new(&bytes) {
-//!         // Make sure the EthernetFrame payload represents an Ipv4Packet
-//!         if let Some(ipv4_packet) = wiretap::Ipv4Packet::new(ethernet_packet.deref().payload()) {
-//!             // Make sure the Ipv4Packet payload represents an TcpSegment
-//!            if let Some(tcp_packet) = wiretap::TcpSegment::new(&ipv4_packet.payload()) {
-//!                 // Print out the interesting information
-//!                 println!("Packet: {}:{} --> {}:{}", ipv4_packet.get_source(), tcp_packet.get_source(), ipv4_packet.get_destination(), tcp_packet.get_destination() )
-//!             }
-//!         }
+//! fn print_to_from(packet: wiretap::LayeredPacket) -> wiretap::LiveControl {
+//!     // Make sure the packet has both an Ipv4Packet and a TcpSegment
+//!     if let (Some(ipv4_packet), Some(tcp_packet)) = (packet.ipv4(), packet.tcp()) {
+//!         // Print out the interesting information
+//!         println!("Packet: {}:{} --> {}:{}", ipv4_packet.get_source(), tcp_packet.get_source(), ipv4_packet.get_destination(), tcp_packet.get_destination() )
 //!     }
+//!     // Keep capturing
+//!     wiretap::LiveControl::Continue
 //! }
-//! 
+//!
 //! fn main() {
 //!     // Create a new PacketCapture with the default interface
 //!     let pc = wiretap::PacketCapture::new_with_default().unwrap();
-//!     // Start a capture on that interface
-//!     let pc = pc.start_live_process(print_to_from);
+//!     // Start a capture on that interface, decoding every layer of each packet as it arrives
+//!     let pc = pc.start_live_process_layered(print_to_from);
 //!     // Stuff happens
 //!     thread::sleep(time::Duration::from_secs(15));
 //!     // Stop the capture
@@ -68,26 +61,118 @@
 //! }
 //! ```
 
+pub mod builder;
+pub use builder::*;
+
+mod capture_loop;
+
+pub mod checksum;
+pub use checksum::*;
+
+pub mod defrag;
+pub use defrag::*;
+
+pub mod dissection;
+pub use dissection::*;
+
 pub mod ethernet_frame;
 pub use ethernet_frame::*;
 
+pub mod icmp_packet;
+pub use icmp_packet::*;
+
+pub mod injector;
+pub use injector::*;
+
 pub mod ipv4_packet;
 pub use ipv4_packet::*;
 
+pub mod ipv6_packet;
+pub use ipv6_packet::*;
+
+pub mod layered_packet;
+pub use layered_packet::*;
+
+pub mod packet_collection;
+pub use packet_collection::*;
+
+pub mod packet_store;
+pub use packet_store::*;
+
+#[cfg(target_os = "linux")]
+mod packet_socket;
+
+pub mod pcap;
+pub use pcap::*;
+
+pub mod ring_buffer;
+pub use ring_buffer::*;
+
+mod spill;
+
+pub mod rewrite;
+pub use rewrite::*;
+
+pub mod rotating_writer;
+pub use rotating_writer::*;
+
+pub mod source;
+pub use source::*;
+
+pub mod statistics;
+pub use statistics::*;
+
 pub mod tcp_packet;
 pub use tcp_packet::*;
 
+pub mod tunnel;
+pub use tunnel::*;
+
+pub mod udp_datagram;
+pub use udp_datagram::*;
+
 pub use pnet::packet::Packet;
 
-use pnet::datalink::Channel::Ethernet;
-use pnet::datalink::{self, NetworkInterface};
+use capture_loop::{
+    CaptureCommand, CaptureLoop, CaptureThread, PoolLoop, ProcessLoop, RingLoop, StoreLoop,
+    Subscriber, SubscriberSink, QUEUE_DEPTH,
+};
+use defrag::Reassembled;
+use dissection::{
+    frame_to_ipv4, frame_to_ipv6, frame_to_transport, ipv4_to_icmp, ipv4_to_tcp, ipv4_to_udp,
+    ipv6_to_icmpv6, ipv6_to_tcp, ipv6_to_udp, validate_ethernet,
+};
+use pnet::datalink;
 use pnet::packet::ethernet::EthernetPacket as pnet_EthernetPacket;
 use pnet::packet::ipv4::Ipv4Packet as pnet_Ipv4Packet;
-use pnet::packet::tcp::TcpPacket as pnet_TcpPacket;
+use pnet::packet::ipv6::Ipv6Packet as pnet_Ipv6Packet;
+use rayon::prelude::*;
+use statistics::CaptureCounters;
 use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter};
 use std::marker::PhantomData;
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// Hand a decoded layer to a live processing callback, skipping packets without it and counting malformed ones
+fn process_decoded<'a>(
+    decoded: Result<Option<&'a [u8]>, Malformation>,
+    counters: &CaptureCounters,
+    callback: impl FnOnce(&'a [u8]) -> LiveControl,
+) -> LiveControl {
+    match decoded {
+        Ok(Some(bytes)) => callback(bytes),
+        Ok(None) => LiveControl::Continue,
+        Err(malformation) => {
+            counters.record_parse_failure(malformation.layer());
+            LiveControl::Continue
+        }
+    }
+}
 
 /// Marker for PacketCapture struct
 #[derive(Debug)]
@@ -100,20 +185,95 @@ pub struct Initialized;
 pub struct Started;
 /// Marker for PacketCapture struct
 #[derive(Debug)]
+pub struct Paused;
+/// Marker for PacketCapture struct
+#[derive(Debug)]
 pub struct Completed;
 
+/// How a live processing pool spreads packets over its workers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Sharding {
+    /// Each packet goes to the next worker with room in its queue
+    #[default]
+    RoundRobin,
+    /// Every packet of a flow goes to the same worker, in the order it was captured
+    ///
+    /// Flows are told apart by `LayeredPacket::flow_hash`, so both directions of a connection share a worker
+    Flow,
+}
+
+/// What a live processing callback wants the capture to do next
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LiveControl {
+    /// Keep capturing
+    #[default]
+    Continue,
+    /// Stop capturing once the callback returns
+    Stop,
+}
+
 /// Basic PacketCapture type
 ///
 /// Marker as PhantomData allow compile-time checking of struct use
 #[derive(Debug)]
 pub struct PacketCapture<State> {
-    interface: NetworkInterface,
-    packets: Arc<Mutex<Vec<Vec<u8>>>>,
-    results: Arc<[Vec<u8>]>,
+    commands: Arc<Mutex<Option<Sender<CaptureCommand>>>>,
+    counters: Arc<CaptureCounters>,
+    decapsulation: Decapsulation,
+    handoff: Arc<Mutex<Option<Receiver<PacketStore>>>>,
+    memory_limit: Option<usize>,
+    pause_signal: Arc<AtomicBool>,
+    results: Arc<PacketStore>,
+    /// Parse failures among the results, counted the first time statistics are asked for
+    results_parse_failures: Arc<OnceLock<ParseFailures>>,
+    source: Arc<dyn PacketSource>,
     state: PhantomData<State>,
     stop_signal: Arc<AtomicBool>,
 }
 
+impl<State> PacketCapture<State> {
+    /// Carry the capture over to its next state
+    fn transition<Next>(&self) -> PacketCapture<Next> {
+        PacketCapture {
+            commands: self.commands.clone(),
+            counters: self.counters.clone(),
+            decapsulation: self.decapsulation,
+            handoff: self.handoff.clone(),
+            memory_limit: self.memory_limit,
+            pause_signal: self.pause_signal.clone(),
+            results: self.results.clone(),
+            results_parse_failures: self.results_parse_failures.clone(),
+            source: self.source.clone(),
+            state: PhantomData,
+            stop_signal: self.stop_signal.clone(),
+        }
+    }
+
+    /// Stop the capture thread and take over the packets it stored
+    fn complete(&self) -> PacketCapture<Completed> {
+        self.stop_signal.store(true, Ordering::Relaxed);
+        self.collect()
+    }
+
+    /// Wait for the capture thread to finish and take over the packets it stored
+    ///
+    /// Panics if the packets were already taken over, or the capture thread failed before handing them over
+    fn collect(&self) -> PacketCapture<Completed> {
+        let receiver = self.handoff.lock().unwrap().take().unwrap_or_else(|| {
+            panic!("Could not take over the captured packets: the capture was already stopped")
+        });
+        let packets = receiver.recv().unwrap_or_else(|_| {
+            panic!("Could not take over the captured packets: the capture thread failed")
+        });
+        self.counters.stop(self.source.dropped_packets());
+        PacketCapture {
+            results: Arc::new(packets),
+            results_parse_failures: Arc::default(),
+            ..self.transition()
+        }
+    }
+}
+
 /// Uninitialized PacketCaptures can be created only
 impl PacketCapture<Uninitialized> {
     /// Create a PacketCapture
@@ -127,13 +287,7 @@ impl PacketCapture<Uninitialized> {
             .find(|iface| iface.name == interface_name)
             .ok_or(format!("Could not find interface '{interface_name}'"))?;
 
-        Ok(PacketCapture {
-            interface,
-            packets: Arc::new(Mutex::new(vec![])),
-            results: Arc::new([]),
-            state: PhantomData,
-            stop_signal: Arc::new(AtomicBool::new(false)),
-        })
+        Ok(PacketCapture::new_from_source(LiveSource::new(interface)))
     }
 
     /// Create a PacketCapture
@@ -145,49 +299,89 @@ impl PacketCapture<Uninitialized> {
             .find(|iface| iface.is_up() && !iface.is_loopback() && !iface.ips.is_empty())
             .ok_or("Could not determine default interface")?;
 
-        Ok(PacketCapture {
-            interface,
-            packets: Arc::new(Mutex::new(vec![])),
-            results: Arc::new([]),
+        Ok(PacketCapture::new_from_source(LiveSource::new(interface)))
+    }
+
+    /// Create a PacketCapture
+    ///
+    /// Takes the path of a pcap or pcapng file and returns an Initialized PacketCapture that reads it
+    pub fn new_from_file(
+        path: impl AsRef<Path>,
+    ) -> Result<PacketCapture<Initialized>, Box<dyn Error>> {
+        Ok(PacketCapture::new_from_source(FileSource::new(path)?))
+    }
+
+    /// Create a PacketCapture
+    ///
+    /// Takes the path of a pcap or pcapng file and returns an Initialized PacketCapture that plays it back as though it were live
+    pub fn new_replay_from_file(
+        path: impl AsRef<Path>,
+        speed: ReplaySpeed,
+    ) -> Result<PacketCapture<Initialized>, Box<dyn Error>> {
+        Ok(PacketCapture::new_from_source(ReplaySource::new(
+            FileSource::new(path)?,
+            speed,
+        )?))
+    }
+
+    /// Create a PacketCapture
+    ///
+    /// Takes raw ethernet frames and returns an Initialized PacketCapture that reads them
+    pub fn new_from_packets(packets: Vec<Vec<u8>>) -> PacketCapture<Initialized> {
+        PacketCapture::new_from_source(MemorySource::new(packets))
+    }
+
+    /// Create a PacketCapture
+    ///
+    /// Takes any PacketSource and returns an Initialized PacketCapture that reads it
+    pub fn new_from_source(source: impl PacketSource + 'static) -> PacketCapture<Initialized> {
+        PacketCapture {
+            commands: Arc::new(Mutex::new(None)),
+            counters: Arc::new(CaptureCounters::default()),
+            decapsulation: Decapsulation::default(),
+            handoff: Arc::new(Mutex::new(None)),
+            memory_limit: None,
+            pause_signal: Arc::new(AtomicBool::new(false)),
+            results: Arc::new(PacketStore::default()),
+            results_parse_failures: Arc::default(),
+            source: Arc::new(source),
             state: PhantomData,
             stop_signal: Arc::new(AtomicBool::new(false)),
-        })
+        }
     }
 }
 
 /// Initialized PacketCaptures can start a capture or a live processing callback
 impl PacketCapture<Initialized> {
+    /// Cap the memory used to store captured packets
+    ///
+    /// Once more than `limit` bytes of packets are held in memory, they are moved to a temporary pcap file.
+    /// Results serve those packets straight from the file, which is mapped into memory rather than read back
+    pub fn set_memory_limit(&mut self, limit: usize) {
+        self.memory_limit = Some(limit);
+    }
+
+    /// Choose whether the ipv4, tcp and udp live processing callbacks get the outermost or innermost packet of tunneled
+    /// traffic
+    ///
+    /// The choice carries over to the `results_as_*` methods once the capture completes
+    pub fn set_decapsulation(&mut self, decapsulation: Decapsulation) {
+        self.decapsulation = decapsulation;
+    }
+
     /// Start capturing
     ///
     /// Stores packets that can be accessed later with the `results` methods
     pub fn start_capture(&self) -> PacketCapture<Started> {
-        let stop_signal = Arc::clone(&self.stop_signal);
-        let interface = self.interface.clone();
-        let mut rx = match datalink::channel(&interface, Default::default()) {
-            Ok(Ethernet(_, rx)) => rx,
-            Ok(_) => panic!("Non-ethernet channel created"),
-            Err(e) => panic!("Could not create channel using interface: {e}"),
-        };
-        let packets = Arc::clone(&self.packets);
-
-        rayon::spawn(move || {
-            while !stop_signal.load(Ordering::Relaxed) {
-                match rx.next() {
-                    Ok(packet) => {
-                        packets.lock().unwrap().push(packet.to_owned());
-                    }
-                    Err(e) => panic!("Could not read packet: {e}"),
-                }
-            }
-        });
+        self.start_loop(StoreLoop::new(self.memory_limit))
+    }
 
-        PacketCapture {
-            interface: self.interface.clone(),
-            packets: self.packets.clone(),
-            results: self.results.clone(),
-            state: PhantomData,
-            stop_signal: self.stop_signal.clone(),
-        }
+    /// Start capturing to files
+    ///
+    /// Packets are written out as they arrive instead of being stored, so the completed capture holds no results.
+    /// The last file is completed when the capture stops
+    pub fn start_capture_to_file(&self, writer: RotatingWriter) -> PacketCapture<Started> {
+        self.start_loop(writer)
     }
 
     /// Start live processing
@@ -197,94 +391,519 @@ impl PacketCapture<Initialized> {
         &self,
         mut callback: impl FnMut(Vec<u8>) + std::marker::Send + 'static,
     ) -> PacketCapture<Started> {
-        let stop_signal = Arc::clone(&self.stop_signal);
-        let interface = self.interface.clone();
-        let mut rx = match datalink::channel(&interface, Default::default()) {
-            Ok(Ethernet(_, rx)) => rx,
-            Ok(_) => panic!("Non-ethernet channel created"),
-            Err(e) => panic!("Could not create channel: {e}"),
-        };
-
-        rayon::spawn(move || {
-            while !stop_signal.load(Ordering::Relaxed) {
-                match rx.next() {
-                    Ok(packet) => {
-                        callback(packet.to_vec());
-                    }
-                    Err(e) => panic!("Could not read packet: {e}"),
-                }
+        self.start_loop(ProcessLoop(move |packet: &[u8], _, _: &CaptureCounters| {
+            callback(packet.to_vec());
+            LiveControl::Continue
+        }))
+    }
+
+    /// Start live processing of ethernet frames
+    ///
+    /// Frames too short to hold an ethernet header are skipped and counted as parse failures
+    pub fn start_live_process_ethernet(
+        &self,
+        mut callback: impl FnMut(EthernetFrame) -> LiveControl + std::marker::Send + 'static,
+    ) -> PacketCapture<Started> {
+        self.start_decoded_process(move |packet, counters| {
+            process_decoded(
+                validate_ethernet(packet).map(|_| Some(packet)),
+                counters,
+                |frame| callback(EthernetFrame::borrowed(frame)),
+            )
+        })
+    }
+
+    /// Start live processing of ipv4 packets
+    ///
+    /// Frames that don't carry ipv4 are skipped, and malformed ones are also counted as parse failures
+    pub fn start_live_process_ipv4(
+        &self,
+        mut callback: impl FnMut(Ipv4Packet) -> LiveControl + std::marker::Send + 'static,
+    ) -> PacketCapture<Started> {
+        let decapsulation = self.decapsulation;
+        self.start_decoded_process(move |packet, counters| {
+            process_decoded(frame_to_ipv4(packet, decapsulation), counters, |ipv4| {
+                callback(Ipv4Packet::borrowed(ipv4))
+            })
+        })
+    }
+
+    /// Start live processing of tcp segments carried by ipv4 or ipv6
+    ///
+    /// Frames that don't carry tcp are skipped, and malformed ones are also counted as parse failures
+    pub fn start_live_process_tcp(
+        &self,
+        mut callback: impl FnMut(TcpSegment) -> LiveControl + std::marker::Send + 'static,
+    ) -> PacketCapture<Started> {
+        let decapsulation = self.decapsulation;
+        self.start_decoded_process(move |packet, counters| {
+            let segment = frame_to_transport(packet, decapsulation, ipv4_to_tcp, ipv6_to_tcp);
+            process_decoded(segment, counters, |segment| {
+                callback(TcpSegment::borrowed(segment))
+            })
+        })
+    }
+
+    /// Start live processing of udp datagrams carried by ipv4 or ipv6
+    ///
+    /// Frames that don't carry udp are skipped, and malformed ones are also counted as parse failures
+    pub fn start_live_process_udp(
+        &self,
+        mut callback: impl FnMut(UdpDatagram) -> LiveControl + std::marker::Send + 'static,
+    ) -> PacketCapture<Started> {
+        let decapsulation = self.decapsulation;
+        self.start_decoded_process(move |packet, counters| {
+            let datagram = frame_to_transport(packet, decapsulation, ipv4_to_udp, ipv6_to_udp);
+            process_decoded(datagram, counters, |datagram| {
+                callback(UdpDatagram::borrowed(datagram))
+            })
+        })
+    }
+
+    /// Start live processing of packets decoded into every recognized layer
+    ///
+    /// Every packet is handed over, including malformed ones, which are also counted as parse failures
+    pub fn start_live_process_layered(
+        &self,
+        mut callback: impl FnMut(LayeredPacket) -> LiveControl + std::marker::Send + 'static,
+    ) -> PacketCapture<Started> {
+        self.start_decoded_process(move |packet, counters| {
+            let layered_packet = LayeredPacket::new(packet);
+            if let Some(malformation) = layered_packet.malformation() {
+                counters.record_parse_failure(malformation.layer());
             }
-        });
+            callback(layered_packet)
+        })
+    }
 
-        PacketCapture {
-            interface: self.interface.clone(),
-            packets: self.packets.clone(),
-            results: self.results.clone(),
-            state: PhantomData,
-            stop_signal: self.stop_signal.clone(),
+    /// Start live processing on a pool of workers
+    ///
+    /// Packets are read on one thread and handed to `workers` threads of a dedicated rayon pool, which call `callback` on them,
+    /// so a slow callback doesn't hold up reading. Packets are dropped, and counted in the statistics, when no worker
+    /// they could go to has room in its queue. Stopping waits for the workers to process the packets already queued
+    pub fn start_live_process_pool(
+        &self,
+        workers: usize,
+        sharding: Sharding,
+        callback: impl Fn(Vec<u8>) + std::marker::Send + Sync + 'static,
+    ) -> PacketCapture<Started> {
+        self.start_loop(PoolLoop::new(workers, sharding, callback))
+    }
+
+    /// Start a ring capture
+    ///
+    /// Keeps the traffic within the `before` window continuously, and calls `trigger` on every packet decoded into its layers.
+    /// When the trigger matches, the packets before it, the triggering packet and the packets within the `after` window are
+    /// handed to `on_event` as a completed capture. Triggers are ignored while the `after` window of an event is being filled.
+    /// An event still being filled when the capture stops is handed over with the packets gathered so far
+    pub fn start_ring_capture(
+        &self,
+        before: RingWindow,
+        after: RingWindow,
+        trigger: impl Fn(&LayeredPacket) -> bool + std::marker::Send + 'static,
+        on_event: impl FnMut(PacketCapture<Completed>) + std::marker::Send + 'static,
+    ) -> PacketCapture<Started> {
+        self.start_loop(RingLoop::new(
+            before,
+            after,
+            trigger,
+            on_event,
+            self.transition(),
+        ))
+    }
+
+    /// Start live processing with a callback that decodes each packet itself
+    fn start_decoded_process(
+        &self,
+        mut process: impl FnMut(&[u8], &CaptureCounters) -> LiveControl + std::marker::Send + 'static,
+    ) -> PacketCapture<Started> {
+        self.start_loop(ProcessLoop(
+            move |packet: &[u8], _, counters: &CaptureCounters| process(packet, counters),
+        ))
+    }
+
+    /// Start a capture thread running a capture loop
+    fn start_loop(&self, capture_loop: impl CaptureLoop) -> PacketCapture<Started> {
+        self.counters.start(self.source.dropped_packets());
+        let (handoff, receiver) = mpsc::sync_channel(1);
+        *self.handoff.lock().unwrap() = Some(receiver);
+        let (command_sender, commands) = mpsc::channel();
+        *self.commands.lock().unwrap() = Some(command_sender);
+
+        CaptureThread {
+            reader: self.source.open().unwrap_or_else(|e| {
+                panic!("Could not open packet source {}: {e}", self.source.name())
+            }),
+            stop_signal: Arc::clone(&self.stop_signal),
+            pause_signal: Arc::clone(&self.pause_signal),
+            counters: Arc::clone(&self.counters),
+            commands,
+            handoff,
         }
+        .spawn(capture_loop);
+
+        self.transition()
     }
 }
 
-/// Started PacketCaptures can stop only
+/// Started PacketCaptures can pause or stop
 impl PacketCapture<Started> {
+    /// Pause capturing
+    ///
+    /// The channel stays open, but packets arriving while paused are dropped rather than recorded
+    pub fn pause_capture(&self) -> PacketCapture<Paused> {
+        self.pause_signal.store(true, Ordering::Relaxed);
+        self.transition()
+    }
+
+    /// Copy the packets captured so far, leaving the capture running
+    ///
+    /// Takes effect once the capture thread finishes its current read. Spilled packets are served from the spill file
+    /// rather than copied, so a snapshot only adds the packets still in memory and an index of the spilled ones.
+    /// Captures that don't store packets return an empty snapshot.
+    /// Panics if the capture thread already finished, in which case `wait_capture` returns the packets
+    pub fn snapshot_capture(&self) -> PacketCapture<Completed> {
+        self.request(CaptureCommand::Snapshot)
+    }
+
+    /// Take over the packets captured so far without copying them, leaving the capture running with no stored packets
+    ///
+    /// Takes effect once the capture thread finishes its current read.
+    /// Captures that don't store packets return an empty result.
+    /// Panics if the capture thread already finished, in which case `wait_capture` returns the packets
+    pub fn drain_capture(&self) -> PacketCapture<Completed> {
+        self.request(CaptureCommand::Drain)
+    }
+
+    /// Call `callback` on every packet that passes `filter`, on top of what the capture does with packets
+    ///
+    /// Takes effect once the capture thread finishes its current read.
+    /// The callback runs on the capture thread, so a slow one holds up reading
+    pub fn subscribe(
+        &self,
+        filter: impl Fn(&LayeredPacket) -> bool + std::marker::Send + 'static,
+        callback: impl FnMut(SystemTime, &[u8]) + std::marker::Send + 'static,
+    ) {
+        self.add_subscriber(Subscriber {
+            filter: Box::new(filter),
+            sink: SubscriberSink::Callback(Box::new(callback)),
+        });
+    }
+
+    /// Receive every packet that passes `filter` on a channel, on top of what the capture does with packets
+    ///
+    /// Takes effect once the capture thread finishes its current read. Packets arriving while the channel is full
+    /// are dropped and counted in the statistics. Dropping the receiver ends the subscription
+    pub fn subscribe_channel(
+        &self,
+        filter: impl Fn(&LayeredPacket) -> bool + std::marker::Send + 'static,
+    ) -> Receiver<(SystemTime, Vec<u8>)> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+        self.add_subscriber(Subscriber {
+            filter: Box::new(filter),
+            sink: SubscriberSink::Channel(sender),
+        });
+        receiver
+    }
+
+    /// Statistics of the capture so far
+    pub fn statistics(&self) -> CaptureStatistics {
+        self.counters.statistics(self.source.dropped_packets())
+    }
+
     /// Stop capturing
     ///
-    /// Not much more to it
+    /// Waits for the capture thread to finish its current read, then takes over the packets it stored without copying them.
+    /// Panics if the capture was already stopped, or the capture thread failed
     pub fn stop_capture(&self) -> PacketCapture<Completed> {
-        self.stop_signal.store(true, Ordering::Relaxed);
+        self.complete()
+    }
+
+    /// Wait for the capture to end on its own
+    ///
+    /// Returns once the source runs out of packets, as files and packets held in memory do,
+    /// or once a live processing callback stops the capture.
+    /// Panics if the capture was already stopped, or the capture thread failed
+    pub fn wait_capture(&self) -> PacketCapture<Completed> {
+        self.collect()
+    }
+
+    /// Register a subscriber with the capture thread
+    fn add_subscriber(&self, subscriber: Subscriber) {
+        if let Some(commands) = self.commands.lock().unwrap().as_ref() {
+            let _ = commands.send(CaptureCommand::Subscribe(subscriber));
+        }
+    }
+
+    /// Send a command to the capture thread and wrap the packets it replies with
+    fn request(
+        &self,
+        command: impl FnOnce(SyncSender<PacketStore>) -> CaptureCommand,
+    ) -> PacketCapture<Completed> {
+        let (reply, response) = mpsc::sync_channel(1);
+        match self.commands.lock().unwrap().as_ref() {
+            Some(commands) => {
+                let _ = commands.send(command(reply));
+            }
+            None => drop(reply),
+        }
         PacketCapture {
-            interface: self.interface.clone(),
-            packets: self.packets.clone(),
-            results: Arc::from(
-                self.packets
-                    .lock()
-                    .unwrap()
-                    .clone()
-                    .into_iter()
-                    .collect::<Vec<_>>(),
-            ),
-            state: PhantomData,
-            stop_signal: self.stop_signal.clone(),
+            results: Arc::new(response.recv().unwrap_or_else(|_| {
+                panic!("Could not reach the capture thread: it already finished")
+            })),
+            results_parse_failures: Arc::default(),
+            ..self.transition()
         }
     }
 }
 
+/// Paused PacketCaptures can resume or stop
+impl PacketCapture<Paused> {
+    /// Resume capturing
+    ///
+    /// Packets are recorded again after those captured before the pause
+    pub fn resume_capture(&self) -> PacketCapture<Started> {
+        self.pause_signal.store(false, Ordering::Relaxed);
+        self.transition()
+    }
+
+    /// Statistics of the capture so far
+    pub fn statistics(&self) -> CaptureStatistics {
+        self.counters.statistics(self.source.dropped_packets())
+    }
+
+    /// Stop capturing
+    ///
+    /// Returns the packets captured before the pause.
+    /// Panics if the capture was already stopped, or the capture thread failed
+    pub fn stop_capture(&self) -> PacketCapture<Completed> {
+        self.complete()
+    }
+}
+
 /// Completed PacketCaptures return results in various formats
+///
+/// Results are decoded in parallel and keep the order the packets were captured in.
+/// Decoded packets borrow the captured bytes rather than copying them
 impl PacketCapture<Completed> {
     /// Results returned as raw vectors of bytes
+    #[deprecated(
+        note = "copies every packet, use results_store to read them where they are stored"
+    )]
     pub fn results_raw(&self) -> Arc<[Vec<u8>]> {
+        self.results.iter().map(<[u8]>::to_vec).collect()
+    }
+
+    /// Results returned as raw bytes, stored in capture order along with their timestamps
+    pub fn results_store(&self) -> Arc<PacketStore> {
         self.results.clone()
     }
 
+    /// Write the captured packets to a pcap file
+    pub fn save_pcap(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = PcapWriter::new(BufWriter::new(File::create(path)?))?;
+        for (timestamp, packet) in self.results.iter_with_timestamps() {
+            writer.write_packet(timestamp, packet)?;
+        }
+        writer.flush()
+    }
+
+    /// Write the captured packets to a pcap file after passing them through a Rewriter
+    pub fn save_pcap_rewritten(
+        &self,
+        path: impl AsRef<Path>,
+        rewriter: &Rewriter,
+    ) -> io::Result<()> {
+        let mut writer = PcapWriter::new(BufWriter::new(File::create(path)?))?;
+        for (timestamp, packet) in self.results.iter_with_timestamps() {
+            writer.write_packet(timestamp, &rewriter.rewrite_packet(packet))?;
+        }
+        writer.flush()
+    }
+
+    /// Statistics of the capture
+    ///
+    /// Parse failures include those of the packets in the results, which are dissected into every recognized layer
+    /// the first time statistics are asked for
+    pub fn statistics(&self) -> CaptureStatistics {
+        let parse_failures = *self.results_parse_failures.get_or_init(|| {
+            self.results
+                .par_iter()
+                .filter_map(|buf| LayeredPacket::new(buf).malformation())
+                .fold(
+                    ParseFailures::default,
+                    |mut parse_failures, malformation| {
+                        parse_failures.count(malformation.layer());
+                        parse_failures
+                    },
+                )
+                .reduce(ParseFailures::default, |a, b| a + b)
+        });
+        let statistics = self.counters.statistics(self.source.dropped_packets());
+        CaptureStatistics {
+            parse_failures: statistics.parse_failures + parse_failures,
+            ..statistics
+        }
+    }
+
+    /// Choose whether the `results_as_*` methods decode the outermost or innermost packet of tunneled traffic
+    pub fn set_decapsulation(&mut self, decapsulation: Decapsulation) {
+        self.decapsulation = decapsulation;
+    }
+
     /// Results returned as ethernet frames
-    pub fn results_as_ethernet(&self) -> EthernetFrameCollection {
-        self.results_raw()
-            .iter()
+    ///
+    /// With `Decapsulation::Innermost`, frames carried by VXLAN, Geneve or GRE are returned instead of the outer frame
+    pub fn results_as_ethernet(&self) -> EthernetFrameCollection<'_> {
+        self.results
+            .par_iter()
             .filter(|buf| pnet_EthernetPacket::new(buf).is_some())
-            .map(|buf| EthernetFrame::from(pnet_EthernetPacket::owned(buf.to_vec()).unwrap()))
+            .map(|buf| match self.decapsulation {
+                Decapsulation::Outermost => buf,
+                Decapsulation::Innermost => tunnel::innermost_ethernet(buf),
+            })
+            .map(EthernetFrame::borrowed)
             .collect::<EthernetFrameCollection>()
     }
 
     /// Results returned as ipv4 packets
-    pub fn results_as_ipv4(&self) -> Ipv4PacketCollection {
-        self.results_as_ethernet()
-            .iter()
-            .filter(|ethernet_frame| pnet_Ipv4Packet::new(ethernet_frame.payload()).is_some())
-            .map(|ethernet_frame| {
-                Ipv4Packet::from(pnet_Ipv4Packet::owned(ethernet_frame.payload().to_vec()).unwrap())
-            })
+    ///
+    /// Only frames with an ipv4 EtherType and a well formed ipv4 header are returned.
+    /// VLAN tags and MPLS labels in front of the header are skipped.
+    /// With `Decapsulation::Innermost`, the packet inside the last tunnel is returned instead of the outer packet
+    pub fn results_as_ipv4(&self) -> Ipv4PacketCollection<'_> {
+        self.results
+            .par_iter()
+            .filter_map(|buf| frame_to_ipv4(buf, self.decapsulation).ok().flatten())
+            .map(Ipv4Packet::borrowed)
             .collect::<Ipv4PacketCollection>()
     }
 
-    /// Results returned as tcp segments
-    pub fn results_as_tcp(&self) -> TcpSegmentCollection {
-        self.results_as_ipv4()
-            .iter()
-            .filter(|ipv4_packet| pnet_TcpPacket::new(ipv4_packet.payload()).is_some())
-            .map(|ipv4_packet| {
-                TcpSegment::from(pnet_TcpPacket::owned(ipv4_packet.payload().to_vec()).unwrap())
+    /// Results returned as ipv4 packets, with fragmented packets reassembled
+    ///
+    /// Fragment sets time out by the time their fragments were captured.
+    /// Reassembled packets take the place of the fragment that completed them
+    pub fn results_defragmented_ipv4(
+        &self,
+        defragmenter: &Defragmenter,
+    ) -> Defragmentation<Ipv4Packet<'_>> {
+        let ipv4_packets: Vec<(SystemTime, &[u8])> = self
+            .results
+            .iter_with_timestamps()
+            .filter_map(|(timestamp, buf)| {
+                let ipv4_packet = frame_to_ipv4(buf, self.decapsulation).ok().flatten()?;
+                Some((timestamp, ipv4_packet))
+            })
+            .collect();
+        let (reassembled, reports) = defragmenter.reassemble_ipv4(
+            ipv4_packets
+                .iter()
+                .map(|(timestamp, packet)| (Some(*timestamp), *packet)),
+        );
+        let packets = reassembled
+            .into_iter()
+            .map(|packet| match packet {
+                Reassembled::Unchanged(index) => Ipv4Packet::borrowed(ipv4_packets[index].1),
+                Reassembled::Rebuilt(packet) => {
+                    Ipv4Packet::from(pnet_Ipv4Packet::owned(packet).unwrap())
+                }
             })
+            .collect();
+        Defragmentation { packets, reports }
+    }
+
+    /// Results returned as ipv6 packets, with packets carrying a fragment header reassembled
+    ///
+    /// Fragment sets time out by the time their fragments were captured.
+    /// Reassembled packets take the place of the fragment that completed them
+    pub fn results_defragmented_ipv6(
+        &self,
+        defragmenter: &Defragmenter,
+    ) -> Defragmentation<Ipv6Packet<'_>> {
+        let ipv6_packets: Vec<(SystemTime, &[u8])> = self
+            .results
+            .iter_with_timestamps()
+            .filter_map(|(timestamp, buf)| {
+                let ipv6_packet = frame_to_ipv6(buf, self.decapsulation).ok().flatten()?;
+                Some((timestamp, ipv6_packet))
+            })
+            .collect();
+        let (reassembled, reports) = defragmenter.reassemble_ipv6(
+            ipv6_packets
+                .iter()
+                .map(|(timestamp, packet)| (Some(*timestamp), *packet)),
+        );
+        let packets = reassembled
+            .into_iter()
+            .map(|packet| match packet {
+                Reassembled::Unchanged(index) => Ipv6Packet::borrowed(ipv6_packets[index].1),
+                Reassembled::Rebuilt(packet) => {
+                    Ipv6Packet::from(pnet_Ipv6Packet::owned(packet).unwrap())
+                }
+            })
+            .collect();
+        Defragmentation { packets, reports }
+    }
+
+    /// Results returned as tcp segments
+    ///
+    /// Only ipv4 packets with the tcp protocol number and a well formed tcp header are returned
+    pub fn results_as_tcp(&self) -> TcpSegmentCollection<'_> {
+        self.results
+            .par_iter()
+            .filter_map(|buf| frame_to_ipv4(buf, self.decapsulation).ok().flatten())
+            .filter_map(|buf| ipv4_to_tcp(buf).ok().flatten())
+            .map(TcpSegment::borrowed)
             .collect::<TcpSegmentCollection>()
     }
+
+    /// Results returned as icmp messages
+    ///
+    /// Only ipv4 packets with the icmp protocol number and a whole icmp header are returned
+    pub fn results_as_icmp(&self) -> IcmpPacketCollection<'_> {
+        self.results
+            .par_iter()
+            .filter_map(|buf| frame_to_ipv4(buf, self.decapsulation).ok().flatten())
+            .filter_map(|buf| ipv4_to_icmp(buf).ok().flatten())
+            .map(IcmpPacket::borrowed)
+            .collect::<IcmpPacketCollection>()
+    }
+
+    /// Results returned as icmpv6 messages
+    ///
+    /// Only ipv6 packets carrying icmpv6 after their extension headers and a whole icmpv6 header are returned
+    pub fn results_as_icmpv6(&self) -> Icmpv6PacketCollection<'_> {
+        self.results
+            .par_iter()
+            .filter_map(|buf| frame_to_ipv6(buf, self.decapsulation).ok().flatten())
+            .filter_map(|buf| ipv6_to_icmpv6(buf).ok().flatten())
+            .map(Icmpv6Packet::borrowed)
+            .collect::<Icmpv6PacketCollection>()
+    }
+
+    /// Results returned as packets decoded into every recognized layer
+    pub fn results_as_layered(&self) -> LayeredPacketCollection<'_> {
+        self.results
+            .par_iter()
+            .map(|buf| LayeredPacket::new(buf))
+            .collect::<LayeredPacketCollection>()
+    }
+
+    /// Results that could not be dissected, along with what was wrong with them
+    ///
+    /// Covers every layer `LayeredPacket` decodes, so it matches the parse failures in `statistics`
+    pub fn results_malformed(&self) -> MalformedPacketCollection {
+        self.results
+            .par_iter()
+            .enumerate()
+            .filter_map(|(index, buf)| {
+                LayeredPacket::new(buf)
+                    .malformation()
+                    .map(|malformation| MalformedPacket {
+                        index,
+                        malformation,
+                    })
+            })
+            .collect::<MalformedPacketCollection>()
+    }
 }
@@ -0,0 +1,165 @@
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Wrapper around an Arc<[T]> shared by every collection of packets
+///
+/// Methods return new collections and leave the original untouched
+#[derive(Debug)]
+pub struct PacketCollection<T>(pub(crate) Arc<[T]>);
+
+impl<T> Clone for PacketCollection<T> {
+    fn clone(&self) -> Self {
+        PacketCollection(self.0.clone())
+    }
+}
+
+impl<T> FromIterator<T> for PacketCollection<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        PacketCollection(iter.into_iter().collect())
+    }
+}
+
+/// Collecting from a parallel iterator keeps the order of the iterator, not the order work finished in
+impl<T: Send> FromParallelIterator<T> for PacketCollection<T> {
+    fn from_par_iter<I: IntoParallelIterator<Item = T>>(par_iter: I) -> Self {
+        PacketCollection::from(par_iter.into_par_iter().collect::<Vec<T>>())
+    }
+}
+
+impl<T> From<Vec<T>> for PacketCollection<T> {
+    fn from(packets: Vec<T>) -> Self {
+        PacketCollection(packets.into())
+    }
+}
+
+impl<T> Deref for PacketCollection<T> {
+    type Target = Arc<[T]>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T: Clone> PacketCollection<T> {
+    /// Get a collection of the packets matching a predicate
+    pub fn filter(&self, predicate: impl Fn(&T) -> bool) -> PacketCollection<T> {
+        self.iter().filter(|p| predicate(p)).cloned().collect()
+    }
+
+    /// Split the collection into the packets matching a predicate and the ones that don't
+    pub fn partition(
+        &self,
+        predicate: impl Fn(&T) -> bool,
+    ) -> (PacketCollection<T>, PacketCollection<T>) {
+        let (matching, rest): (Vec<T>, Vec<T>) = self.iter().cloned().partition(|p| predicate(p));
+        (matching.into(), rest.into())
+    }
+
+    /// Get a collection of the packets sorted with a comparator
+    ///
+    /// The sort is stable, so equal packets keep their capture order
+    pub fn sorted_by(&self, compare: impl Fn(&T, &T) -> Ordering) -> PacketCollection<T> {
+        let mut packets = self.to_vec();
+        packets.sort_by(compare);
+        packets.into()
+    }
+
+    /// Get a collection of the packets sorted by a key
+    ///
+    /// The sort is stable, so packets with equal keys keep their capture order
+    pub fn sorted_by_key<K: Ord>(&self, key: impl Fn(&T) -> K) -> PacketCollection<T> {
+        let mut packets = self.to_vec();
+        packets.sort_by_key(key);
+        packets.into()
+    }
+
+    /// Get a collection keeping only the first packet for each key
+    pub fn dedup_by_key<K: Hash + Eq>(&self, key: impl Fn(&T) -> K) -> PacketCollection<T> {
+        let mut seen = HashSet::new();
+        self.iter()
+            .filter(|p| seen.insert(key(p)))
+            .cloned()
+            .collect()
+    }
+
+    /// Group the packets by a key, keeping capture order within each group
+    pub fn group_by<K: Hash + Eq>(&self, key: impl Fn(&T) -> K) -> HashMap<K, PacketCollection<T>> {
+        let mut groups: HashMap<K, Vec<T>> = HashMap::new();
+        for p in self.iter() {
+            groups.entry(key(p)).or_default().push(p.clone());
+        }
+        groups
+            .into_iter()
+            .map(|(k, packets)| (k, packets.into()))
+            .collect()
+    }
+}
+
+impl<T> PacketCollection<T> {
+    /// Get a collection of new values computed from each packet
+    pub fn map<U>(&self, f: impl Fn(&T) -> U) -> PacketCollection<U> {
+        self.iter().map(f).collect()
+    }
+}
+
+/// Parallel variants, which keep the capture order of the packets
+impl<T: Clone + Send + Sync> PacketCollection<T> {
+    /// Get a collection of the packets matching a predicate, testing packets in parallel
+    pub fn par_filter(&self, predicate: impl Fn(&T) -> bool + Sync + Send) -> PacketCollection<T> {
+        self.par_iter().filter(|p| predicate(p)).cloned().collect()
+    }
+
+    /// Split the collection into the packets matching a predicate and the ones that don't, testing packets in parallel
+    pub fn par_partition(
+        &self,
+        predicate: impl Fn(&T) -> bool + Sync + Send,
+    ) -> (PacketCollection<T>, PacketCollection<T>) {
+        let (matching, rest): (Vec<T>, Vec<T>) =
+            self.par_iter().cloned().partition(|p| predicate(p));
+        (matching.into(), rest.into())
+    }
+
+    /// Get a collection of the packets sorted with a comparator, sorting in parallel
+    pub fn par_sorted_by(
+        &self,
+        compare: impl Fn(&T, &T) -> Ordering + Sync,
+    ) -> PacketCollection<T> {
+        let mut packets = self.to_vec();
+        packets.par_sort_by(compare);
+        packets.into()
+    }
+
+    /// Get a collection of the packets sorted by a key, sorting in parallel
+    pub fn par_sorted_by_key<K: Ord>(&self, key: impl Fn(&T) -> K + Sync) -> PacketCollection<T> {
+        let mut packets = self.to_vec();
+        packets.par_sort_by_key(key);
+        packets.into()
+    }
+
+    /// Group the packets by a key, computing keys in parallel
+    pub fn par_group_by<K: Hash + Eq + Send>(
+        &self,
+        key: impl Fn(&T) -> K + Sync + Send,
+    ) -> HashMap<K, PacketCollection<T>> {
+        let keys = self.par_iter().map(key).collect::<Vec<K>>();
+        let mut groups: HashMap<K, Vec<T>> = HashMap::new();
+        for (k, p) in keys.into_iter().zip(self.iter()) {
+            groups.entry(k).or_default().push(p.clone());
+        }
+        groups
+            .into_iter()
+            .map(|(k, packets)| (k, packets.into()))
+            .collect()
+    }
+}
+
+impl<T: Sync> PacketCollection<T> {
+    /// Get a collection of new values computed from each packet in parallel
+    pub fn par_map<U: Send>(&self, f: impl Fn(&T) -> U + Sync + Send) -> PacketCollection<U> {
+        self.par_iter().map(f).collect()
+    }
+}
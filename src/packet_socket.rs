@@ -0,0 +1,141 @@
+use pnet::datalink::NetworkInterface;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Raw packet socket bound to one interface, receiving every ethernet frame on it
+///
+/// Kept by wiretap rather than pnet so the kernel's statistics for the socket can be read
+#[derive(Debug)]
+pub(crate) struct PacketSocket {
+    fd: OwnedFd,
+    /// Packets the kernel dropped on the socket, gathered from the statistics it resets on every read
+    drops: Arc<AtomicU64>,
+}
+
+impl PacketSocket {
+    /// Open a promiscuous packet socket on an interface, adding the packets it drops to `drops`
+    pub(crate) fn open(
+        interface: &NetworkInterface,
+        read_timeout: Duration,
+        drops: Arc<AtomicU64>,
+    ) -> io::Result<PacketSocket> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        // Safety: plain system call, and the descriptor it returns is owned by nothing else
+        let fd = match unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                i32::from(protocol),
+            )
+        } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let socket = PacketSocket { fd, drops };
+
+        // Safety: sockaddr_ll is plain old data, so all zeroes is a valid value
+        let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as u16;
+        address.sll_protocol = protocol;
+        address.sll_ifindex = interface.index as i32;
+        // Safety: the address outlives the call and its length is passed along with it
+        socket.check(unsafe {
+            libc::bind(
+                socket.fd.as_raw_fd(),
+                &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        })?;
+
+        let membership = libc::packet_mreq {
+            mr_ifindex: interface.index as i32,
+            mr_type: libc::PACKET_MR_PROMISC as u16,
+            mr_alen: 0,
+            mr_address: [0; 8],
+        };
+        socket.set_option(libc::SOL_PACKET, libc::PACKET_ADD_MEMBERSHIP, &membership)?;
+        let timeout = libc::timeval {
+            tv_sec: read_timeout.as_secs() as libc::time_t,
+            tv_usec: read_timeout.subsec_micros() as libc::suseconds_t,
+        };
+        socket.set_option(libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout)?;
+        Ok(socket)
+    }
+
+    /// Receive the next frame into `buffer`, returning its length
+    ///
+    /// Fails with `TimedOut` when no frame arrived before the read timeout
+    pub(crate) fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        // Safety: the kernel writes at most buffer.len() bytes into the buffer
+        let received = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if received < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => {
+                    Err(io::Error::from(io::ErrorKind::TimedOut))
+                }
+                _ => Err(error),
+            };
+        }
+        Ok(received as usize)
+    }
+
+    /// Add the packets the kernel dropped since the last call to the running total
+    pub(crate) fn count_drops(&self) {
+        // Safety: tpacket_stats is plain old data, so all zeroes is a valid value
+        let mut statistics: libc::tpacket_stats = unsafe { mem::zeroed() };
+        let mut length = mem::size_of::<libc::tpacket_stats>() as libc::socklen_t;
+        // Safety: the kernel writes at most `length` bytes into the statistics
+        let result = unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut statistics as *mut libc::tpacket_stats as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        if result == 0 {
+            self.drops
+                .fetch_add(u64::from(statistics.tp_drops), Ordering::Relaxed);
+        }
+    }
+
+    fn set_option<T>(&self, level: i32, name: i32, value: &T) -> io::Result<()> {
+        // Safety: the value outlives the call and its length is passed along with it
+        self.check(unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                level,
+                name,
+                value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        })
+    }
+
+    fn check(&self, result: i32) -> io::Result<()> {
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for PacketSocket {
+    /// Count the last drops before the socket and its statistics go away
+    fn drop(&mut self) {
+        self.count_drops();
+    }
+}
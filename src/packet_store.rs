@@ -0,0 +1,178 @@
+use crate::spill::MappedSpill;
+use rayon::prelude::*;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Size of the arena chunks packets are copied into
+///
+/// Packets larger than a chunk get a chunk of their own
+pub const ARENA_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Where a captured packet lives in a PacketStore, along with when it arrived
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketRecord {
+    pub timestamp: SystemTime,
+    pub length: usize,
+    chunk: usize,
+    offset: usize,
+}
+
+/// Bytes holding the packets of a PacketStore
+#[derive(Clone, Debug)]
+enum Chunk {
+    /// Packets copied into memory
+    Memory(Vec<u8>),
+    /// Packets spilled to disk, read from the file as they are needed
+    Mapped(Arc<MappedSpill>),
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Chunk::Memory(chunk) => chunk,
+            Chunk::Mapped(mapped_spill) => mapped_spill,
+        }
+    }
+}
+
+/// Arena holding captured packets back to back in large preallocated chunks
+///
+/// The capture thread is the only writer, so storing a packet takes no lock and copies it exactly once.
+/// Packets spilled to disk past a memory limit are served from the spill file instead of being read back
+#[derive(Clone, Debug, Default)]
+pub struct PacketStore {
+    chunks: Vec<Chunk>,
+    records: Vec<PacketRecord>,
+}
+
+impl PacketStore {
+    /// Create a PacketStore with its first chunk already allocated
+    pub(crate) fn preallocated() -> PacketStore {
+        PacketStore {
+            chunks: vec![Chunk::Memory(Vec::with_capacity(ARENA_CHUNK_SIZE))],
+            records: vec![],
+        }
+    }
+
+    /// Copy a packet into the arena
+    pub(crate) fn push(&mut self, packet: &[u8], timestamp: SystemTime) {
+        let fits = matches!(
+            self.chunks.last(),
+            Some(Chunk::Memory(chunk)) if chunk.capacity() - chunk.len() >= packet.len()
+        );
+        if !fits {
+            self.chunks.push(Chunk::Memory(Vec::with_capacity(
+                ARENA_CHUNK_SIZE.max(packet.len()),
+            )));
+        }
+        let chunk = self.chunks.len() - 1;
+        let Some(Chunk::Memory(bytes)) = self.chunks.last_mut() else {
+            unreachable!("the last chunk was just checked to be in memory");
+        };
+        let offset = bytes.len();
+        bytes.extend_from_slice(packet);
+        self.records.push(PacketRecord {
+            timestamp,
+            length: packet.len(),
+            chunk,
+            offset,
+        });
+    }
+
+    /// Add packets served from part of a spill file, given when they were captured and where they sit in it
+    pub(crate) fn push_mapped(
+        &mut self,
+        mapped_spill: Arc<MappedSpill>,
+        packets: impl IntoIterator<Item = (SystemTime, usize, usize)>,
+    ) {
+        let chunk = self.chunks.len();
+        self.chunks.push(Chunk::Mapped(mapped_spill));
+        self.records.extend(
+            packets
+                .into_iter()
+                .map(|(timestamp, offset, length)| PacketRecord {
+                    timestamp,
+                    length,
+                    chunk,
+                    offset,
+                }),
+        );
+    }
+
+    /// Number of packet bytes held in memory, leaving out those served from a spill file
+    pub fn bytes_stored(&self) -> usize {
+        self.chunks
+            .iter()
+            .map(|chunk| match chunk {
+                Chunk::Memory(chunk) => chunk.len(),
+                Chunk::Mapped(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Forget every packet, keeping the first chunk allocated for reuse if it is in memory
+    pub(crate) fn clear(&mut self) {
+        self.chunks.truncate(1);
+        match self.chunks.first_mut() {
+            Some(Chunk::Memory(chunk)) => chunk.clear(),
+            Some(Chunk::Mapped(_)) => self.chunks.clear(),
+            None => {}
+        }
+        self.records.clear();
+    }
+
+    /// Move the packets of another store after the packets of this one, without copying them
+    pub(crate) fn append(&mut self, other: PacketStore) {
+        let chunk_offset = self.chunks.len();
+        self.chunks.extend(other.chunks);
+        self.records
+            .extend(other.records.into_iter().map(|record| PacketRecord {
+                chunk: record.chunk + chunk_offset,
+                ..record
+            }));
+    }
+
+    /// Number of packets in the store
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Return true if the store holds no packets
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Get the bytes of the packet at an index
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        self.records.get(index).map(|record| self.bytes(record))
+    }
+
+    /// Get where and when every packet was captured, in capture order
+    pub fn records(&self) -> &[PacketRecord] {
+        &self.records
+    }
+
+    /// Iterate over the bytes of the packets in capture order
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.records.iter().map(|record| self.bytes(record))
+    }
+
+    /// Iterate over the bytes of the packets in parallel, keeping capture order when collected
+    pub fn par_iter(&self) -> impl IndexedParallelIterator<Item = &[u8]> + '_ {
+        self.records.par_iter().map(|record| self.bytes(record))
+    }
+
+    /// Iterate over the packets along with the time they were captured
+    pub fn iter_with_timestamps(&self) -> impl Iterator<Item = (SystemTime, &[u8])> + '_ {
+        self.records
+            .iter()
+            .map(|record| (record.timestamp, self.bytes(record)))
+    }
+
+    fn bytes(&self, record: &PacketRecord) -> &[u8] {
+        &self.chunks[record.chunk][record.offset..record.offset + record.length]
+    }
+}
@@ -0,0 +1,496 @@
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Magic number of pcap files with microsecond timestamps
+const MAGIC_MICROSECONDS: u32 = 0xa1b2c3d4;
+/// Magic number of pcap files with nanosecond timestamps
+const MAGIC_NANOSECONDS: u32 = 0xa1b23c4d;
+/// Block type of pcapng section header blocks
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+/// Block type of pcapng interface description blocks
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+/// Block type of pcapng enhanced packet blocks
+const PCAPNG_ENHANCED_PACKET: u32 = 0x00000006;
+/// Block type of pcapng simple packet blocks
+const PCAPNG_SIMPLE_PACKET: u32 = 0x00000003;
+/// Option code of the timestamp resolution of a pcapng interface
+const PCAPNG_IF_TSRESOL: u16 = 9;
+/// Byte-order magic of pcapng section header blocks
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+/// Link type of ethernet frames
+pub const LINKTYPE_ETHERNET: u32 = 1;
+/// Largest packet written to or accepted from a pcap file
+pub const PCAP_SNAPLEN: u32 = 262144;
+
+/// Writer for the classic libpcap file format, with microsecond timestamps and ethernet link type
+#[derive(Debug)]
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Create a PcapWriter
+    ///
+    /// Writes the pcap global header right away
+    pub fn new(mut writer: W) -> io::Result<PcapWriter<W>> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_MICROSECONDS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(PcapWriter { writer })
+    }
+
+    /// Write a packet along with the time it was captured
+    pub fn write_packet(&mut self, timestamp: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let captured_length = packet.len().min(PCAP_SNAPLEN as usize);
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        header.extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+        header.extend_from_slice(&(captured_length as u32).to_le_bytes());
+        header.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&packet[..captured_length])
+    }
+
+    /// Flush buffered packets to the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Get back the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Writer for the pcapng file format, with one ethernet interface and microsecond timestamps
+#[derive(Debug)]
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Create a PcapngWriter
+    ///
+    /// Writes the section header and interface description blocks right away
+    pub fn new(mut writer: W) -> io::Result<PcapngWriter<W>> {
+        let mut header = Vec::with_capacity(48);
+        header.extend_from_slice(&PCAPNG_SECTION_HEADER.to_le_bytes());
+        header.extend_from_slice(&28u32.to_le_bytes());
+        header.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&(-1i64).to_le_bytes());
+        header.extend_from_slice(&28u32.to_le_bytes());
+        header.extend_from_slice(&PCAPNG_INTERFACE_DESCRIPTION.to_le_bytes());
+        header.extend_from_slice(&20u32.to_le_bytes());
+        header.extend_from_slice(&(LINKTYPE_ETHERNET as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&20u32.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(PcapngWriter { writer })
+    }
+
+    /// Write a packet along with the time it was captured
+    pub fn write_packet(&mut self, timestamp: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let microseconds = since_epoch.as_micros() as u64;
+        let captured_length = packet.len().min(PCAP_SNAPLEN as usize);
+        let padding = (4 - captured_length % 4) % 4;
+        let block_length = (32 + captured_length + padding) as u32;
+        let mut header = Vec::with_capacity(28);
+        header.extend_from_slice(&PCAPNG_ENHANCED_PACKET.to_le_bytes());
+        header.extend_from_slice(&block_length.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&((microseconds >> 32) as u32).to_le_bytes());
+        header.extend_from_slice(&(microseconds as u32).to_le_bytes());
+        header.extend_from_slice(&(captured_length as u32).to_le_bytes());
+        header.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&packet[..captured_length])?;
+        self.writer.write_all(&[0u8; 3][..padding])?;
+        self.writer.write_all(&block_length.to_le_bytes())
+    }
+
+    /// Flush buffered packets to the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Get back the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reader for the classic libpcap file format, in either byte order and timestamp precision
+#[derive(Debug)]
+pub struct PcapReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    nanoseconds: bool,
+    link_type: u32,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Create a PcapReader
+    ///
+    /// Reads and checks the pcap global header right away
+    pub fn new(mut reader: R) -> io::Result<PcapReader<R>> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        let (big_endian, nanoseconds) =
+            match u32::from_le_bytes([header[0], header[1], header[2], header[3]]) {
+                MAGIC_MICROSECONDS => (false, false),
+                MAGIC_NANOSECONDS => (false, true),
+                magic if magic.swap_bytes() == MAGIC_MICROSECONDS => (true, false),
+                magic if magic.swap_bytes() == MAGIC_NANOSECONDS => (true, true),
+                magic => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Not a pcap file, magic number {magic:#010x}"),
+                    ))
+                }
+            };
+        let mut pcap_reader = PcapReader {
+            reader,
+            big_endian,
+            nanoseconds,
+            link_type: 0,
+        };
+        pcap_reader.link_type = pcap_reader.read_u32(&header[20..24]);
+        Ok(pcap_reader)
+    }
+
+    /// Get the link type of the packets in the file
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+
+    /// Read the next packet along with the time it was captured
+    ///
+    /// Returns `Ok(None)` at the end of the file
+    pub fn next_packet(&mut self) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let seconds = self.read_u32(&header[0..4]);
+        let fraction = self.read_u32(&header[4..8]);
+        let captured_length = self.read_u32(&header[8..12]);
+        if captured_length > PCAP_SNAPLEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Packet of {captured_length} bytes is larger than the snaplen"),
+            ));
+        }
+        let mut packet = vec![0u8; captured_length as usize];
+        self.reader.read_exact(&mut packet)?;
+        let fraction = match self.nanoseconds {
+            true => Duration::from_nanos(u64::from(fraction)),
+            false => Duration::from_micros(u64::from(fraction)),
+        };
+        let timestamp = UNIX_EPOCH + Duration::from_secs(u64::from(seconds)) + fraction;
+        Ok(Some((timestamp, packet)))
+    }
+
+    fn read_u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<(SystemTime, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+/// Interface described in a pcapng section
+#[derive(Clone, Copy, Debug)]
+struct PcapngInterface {
+    link_type: u32,
+    ticks_per_second: u64,
+}
+
+/// Reader for the pcapng file format
+///
+/// Reads enhanced and simple packet blocks from every section and ethernet interface, skipping other blocks
+/// and the packets of interfaces with any other link type.
+/// Simple packet blocks carry no timestamp, so their packets are given the epoch
+#[derive(Debug)]
+pub struct PcapngReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    interfaces: Vec<PcapngInterface>,
+}
+
+impl<R: Read> PcapngReader<R> {
+    /// Create a PcapngReader
+    ///
+    /// Reads and checks the first section header block right away
+    pub fn new(mut reader: R) -> io::Result<PcapngReader<R>> {
+        let mut block_type = [0u8; 4];
+        reader.read_exact(&mut block_type)?;
+        if u32::from_le_bytes(block_type) != PCAPNG_SECTION_HEADER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a pcapng file, missing section header block",
+            ));
+        }
+        let mut pcapng_reader = PcapngReader {
+            reader,
+            big_endian: false,
+            interfaces: vec![],
+        };
+        pcapng_reader.read_section_header()?;
+        Ok(pcapng_reader)
+    }
+
+    /// Read the next packet along with the time it was captured
+    ///
+    /// Returns `Ok(None)` at the end of the file
+    pub fn next_packet(&mut self) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        loop {
+            let mut block_type = [0u8; 4];
+            match self.reader.read_exact(&mut block_type) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            if u32::from_le_bytes(block_type) == PCAPNG_SECTION_HEADER {
+                self.read_section_header()?;
+                continue;
+            }
+            let block_type = self.read_u32(&block_type);
+            let Some(body) = self.read_block_body(block_type)? else {
+                continue;
+            };
+            match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION => self.read_interface(&body)?,
+                PCAPNG_ENHANCED_PACKET => {
+                    if let Some(packet) = self.read_enhanced_packet(&body)? {
+                        return Ok(Some(packet));
+                    }
+                }
+                _ => {
+                    if let Some(packet) = self.read_simple_packet(&body)? {
+                        return Ok(Some((UNIX_EPOCH, packet)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read a section header block, once its block type has been read
+    fn read_section_header(&mut self) -> io::Result<()> {
+        let mut header = [0u8; 8];
+        self.reader.read_exact(&mut header)?;
+        self.big_endian = match u32::from_le_bytes([header[4], header[5], header[6], header[7]]) {
+            PCAPNG_BYTE_ORDER_MAGIC => false,
+            magic if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+            magic => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Not a pcapng file, byte-order magic {magic:#010x}"),
+                ))
+            }
+        };
+        let block_length = self.read_u32(&header[0..4]) as usize;
+        if block_length < 28 || block_length % 4 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid pcapng section header length {block_length}"),
+            ));
+        }
+        self.skip(block_length - 12)?;
+        self.interfaces.clear();
+        Ok(())
+    }
+
+    /// Read the body of a block, once its block type has been read, checking the trailing length
+    ///
+    /// Only packet blocks are held to the snaplen. Blocks other than packets and interface descriptions are
+    /// skipped by their length, returning `None`
+    fn read_block_body(&mut self, block_type: u32) -> io::Result<Option<Vec<u8>>> {
+        let mut length = [0u8; 4];
+        self.reader.read_exact(&mut length)?;
+        let block_length = self.read_u32(&length) as usize;
+        let packet_block = matches!(block_type, PCAPNG_ENHANCED_PACKET | PCAPNG_SIMPLE_PACKET);
+        if block_length < 12
+            || block_length % 4 != 0
+            || packet_block && block_length > PCAP_SNAPLEN as usize + 64
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid pcapng block length {block_length}"),
+            ));
+        }
+        let body = match packet_block || block_type == PCAPNG_INTERFACE_DESCRIPTION {
+            true => {
+                // Filled as it is read rather than allocated up front, as the length may be corrupt
+                let mut body = vec![];
+                (&mut self.reader)
+                    .take(block_length as u64 - 12)
+                    .read_to_end(&mut body)?;
+                if body.len() != block_length - 12 {
+                    return Err(truncated_block());
+                }
+                Some(body)
+            }
+            false => {
+                self.skip(block_length - 12)?;
+                None
+            }
+        };
+        self.reader.read_exact(&mut length)?;
+        let trailing_length = self.read_u32(&length) as usize;
+        if trailing_length != block_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Pcapng block length {block_length} doesn't match trailing length {trailing_length}"),
+            ));
+        }
+        Ok(body)
+    }
+
+    /// Skip past `length` bytes without holding on to them
+    fn skip(&mut self, length: usize) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.reader).take(length as u64), &mut io::sink())?;
+        match skipped == length as u64 {
+            true => Ok(()),
+            false => Err(truncated_block()),
+        }
+    }
+
+    fn read_interface(&mut self, body: &[u8]) -> io::Result<()> {
+        if body.len() < 8 {
+            return Err(invalid_block("interface description"));
+        }
+        let link_type = u32::from(self.read_u16(&body[0..2]));
+        let mut ticks_per_second = 1_000_000;
+        let mut options = &body[8..];
+        while options.len() >= 4 {
+            let code = self.read_u16(&options[0..2]);
+            let length = usize::from(self.read_u16(&options[2..4]));
+            let value = options
+                .get(4..4 + length)
+                .ok_or_else(|| invalid_block("interface option"))?;
+            if code == PCAPNG_IF_TSRESOL && length == 1 {
+                ticks_per_second = match value[0] & 0x80 {
+                    0 => 10u64.checked_pow(u32::from(value[0])),
+                    _ => 2u64.checked_pow(u32::from(value[0] & 0x7f)),
+                }
+                .ok_or_else(|| invalid_block("interface timestamp resolution"))?;
+            }
+            options = options
+                .get(4 + length.next_multiple_of(4)..)
+                .unwrap_or_default();
+        }
+        self.interfaces.push(PcapngInterface {
+            link_type,
+            ticks_per_second,
+        });
+        Ok(())
+    }
+
+    /// Read an enhanced packet block, returning `None` for packets of non-ethernet interfaces
+    fn read_enhanced_packet(&self, body: &[u8]) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        if body.len() < 20 {
+            return Err(invalid_block("enhanced packet"));
+        }
+        let interface = self
+            .interfaces
+            .get(self.read_u32(&body[0..4]) as usize)
+            .ok_or_else(|| invalid_block("enhanced packet interface"))?;
+        if interface.link_type != LINKTYPE_ETHERNET {
+            return Ok(None);
+        }
+        let ticks =
+            u64::from(self.read_u32(&body[4..8])) << 32 | u64::from(self.read_u32(&body[8..12]));
+        let captured_length = self.read_length(body, 12)?;
+        let packet = body
+            .get(20..20 + captured_length)
+            .ok_or_else(|| invalid_block("enhanced packet"))?;
+        let seconds = ticks / interface.ticks_per_second;
+        let nanoseconds = u128::from(ticks % interface.ticks_per_second) * 1_000_000_000
+            / u128::from(interface.ticks_per_second);
+        let timestamp =
+            UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_nanos(nanoseconds as u64);
+        Ok(Some((timestamp, packet.to_vec())))
+    }
+
+    /// Read a simple packet block, which always belongs to the first interface of the section,
+    /// returning `None` when that interface isn't ethernet
+    fn read_simple_packet(&self, body: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let interface = self
+            .interfaces
+            .first()
+            .ok_or_else(|| invalid_block("simple packet interface"))?;
+        if interface.link_type != LINKTYPE_ETHERNET {
+            return Ok(None);
+        }
+        let captured_length = self.read_length(body, 0)?.min(body.len().saturating_sub(4));
+        Ok(Some(body[4..4 + captured_length].to_vec()))
+    }
+
+    fn read_length(&self, body: &[u8], offset: usize) -> io::Result<usize> {
+        let length = body
+            .get(offset..offset + 4)
+            .map(|bytes| self.read_u32(bytes) as usize)
+            .ok_or_else(|| invalid_block("packet"))?;
+        Ok(length)
+    }
+
+    fn read_u16(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        }
+    }
+
+    fn read_u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapngReader<R> {
+    type Item = io::Result<(SystemTime, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+fn truncated_block() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Pcapng block cut short by the end of the file",
+    )
+}
+
+fn invalid_block(block: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Malformed pcapng {block} block"),
+    )
+}
@@ -0,0 +1,409 @@
+use crate::checksum::{fix_transport_checksum, update_checksum, PseudoHeader};
+use crate::dissection::{
+    validate_ethernet, validate_ipv4, validate_ipv6, validate_tcp, validate_udp,
+};
+use crate::ethernet_frame::strip_frame_tags;
+use crate::ipv6_packet::upper_layer;
+use crate::pcap::PcapWriter;
+use crate::source::{FileSource, PacketSource};
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::util;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+use std::path::Path;
+
+/// Rewrites the addresses, ports and payloads of packets, like tcprewrite
+///
+/// Only the outermost IP packet and the TCP or UDP header it carries are rewritten
+#[derive(Clone, Debug)]
+pub struct Rewriter {
+    ip_maps: Vec<(IpNetwork, IpNetwork)>,
+    port_maps: HashMap<u16, u16>,
+    mac_maps: HashMap<MacAddr, MacAddr>,
+    truncate: Option<usize>,
+    fix_checksums: bool,
+}
+
+impl Default for Rewriter {
+    fn default() -> Self {
+        Rewriter::new()
+    }
+}
+
+impl Rewriter {
+    /// Create a Rewriter that leaves packets as they are apart from fixing their checksums
+    pub fn new() -> Rewriter {
+        Rewriter {
+            ip_maps: vec![],
+            port_maps: HashMap::new(),
+            mac_maps: HashMap::new(),
+            truncate: None,
+            fix_checksums: true,
+        }
+    }
+
+    /// Create a Rewriter from a config with one rule per line
+    ///
+    /// ```text
+    /// # Comments start with a hash
+    /// ip 10.0.0.0/8 192.168.0.0/16
+    /// ip 172.16.0.1 172.16.0.2
+    /// port 80 8080
+    /// mac 00:11:22:33:44:55 66:77:88:99:aa:bb
+    /// truncate 64
+    /// checksums off
+    /// ```
+    pub fn from_config(config: &str) -> io::Result<Rewriter> {
+        let mut rewriter = Rewriter::new();
+        for (index, line) in config.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            rewriter.apply_rule(&words).map_err(|message| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: {message}", index + 1),
+                )
+            })?;
+        }
+        Ok(rewriter)
+    }
+
+    /// Create a Rewriter from a config file, in the format read by `from_config`
+    pub fn load_config(path: impl AsRef<Path>) -> io::Result<Rewriter> {
+        Rewriter::from_config(&fs::read_to_string(path)?)
+    }
+
+    fn apply_rule(&mut self, words: &[&str]) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(word: &str) -> Result<T, String> {
+            word.parse()
+                .map_err(|_| format!("Could not parse '{word}'"))
+        }
+        match words {
+            [] => {}
+            ["ip", from, to] => {
+                let (from, to) = (parse(from)?, parse(to)?);
+                if !same_family(from, to) {
+                    return Err(format!("Cannot map {from} to {to}"));
+                }
+                self.add_ip_map(from, to);
+            }
+            ["port", from, to] => self.add_port_map(parse(from)?, parse(to)?),
+            ["mac", from, to] => self.add_mac_map(parse(from)?, parse(to)?),
+            ["truncate", length] => self.set_truncate(parse(length)?),
+            ["checksums", "on"] => self.set_fix_checksums(true),
+            ["checksums", "off"] => self.set_fix_checksums(false),
+            _ => return Err(format!("Unknown rule '{}'", words.join(" "))),
+        }
+        Ok(())
+    }
+
+    /// Map addresses in a network to the same hosts in another network
+    ///
+    /// A single address is a network with a full prefix. Host bits that don't fit in the new network are dropped,
+    /// and the first map containing an address is the one used
+    ///
+    /// Panics if the networks aren't both IPv4 or both IPv6
+    pub fn add_ip_map(&mut self, from: IpNetwork, to: IpNetwork) {
+        assert!(same_family(from, to), "Cannot map {from} to {to}");
+        self.ip_maps.push((from, to));
+    }
+
+    /// Map a TCP or UDP port, whether it is the source or destination
+    pub fn add_port_map(&mut self, from: u16, to: u16) {
+        self.port_maps.insert(from, to);
+    }
+
+    /// Map a MAC address, whether it is the source or destination
+    pub fn add_mac_map(&mut self, from: MacAddr, to: MacAddr) {
+        self.mac_maps.insert(from, to);
+    }
+
+    /// Keep at most `length` bytes of the payload after the TCP or UDP header, or after the IP header for other protocols
+    ///
+    /// The IP and UDP lengths are updated to match
+    pub fn set_truncate(&mut self, length: usize) {
+        self.truncate = Some(length);
+    }
+
+    /// Choose whether the IPv4, TCP, UDP and ICMP checksums are recomputed, which they are by default
+    ///
+    /// Checksums that were wrong before rewriting are fixed too, apart from transport checksums of fragments, which cover
+    /// data the fragment doesn't hold. The first fragment of a TCP, UDP or ICMPv6 message has its checksum updated for
+    /// the rewritten addresses and ports instead, unless its payload was truncated
+    pub fn set_fix_checksums(&mut self, fix_checksums: bool) {
+        self.fix_checksums = fix_checksums;
+    }
+
+    /// Rewrite a raw ethernet frame
+    ///
+    /// Frames that aren't IP, or whose IP header is malformed, only get their MAC addresses mapped
+    pub fn rewrite_packet(&self, frame: &[u8]) -> Vec<u8> {
+        let mut frame = frame.to_vec();
+        if validate_ethernet(&frame).is_err() {
+            return frame;
+        }
+        for range in [0..6, 6..12] {
+            let mac = MacAddr::from(<[u8; 6]>::try_from(&frame[range.clone()]).unwrap());
+            if let Some(mapped) = self.mac_maps.get(&mac) {
+                frame[range].copy_from_slice(&mapped.octets());
+            }
+        }
+        let stripped = strip_frame_tags(&frame);
+        let (ethertype, network) = (stripped.ethertype, frame.len() - stripped.payload.len());
+        match ethertype {
+            EtherTypes::Ipv4 if validate_ipv4(&frame[network..]).is_ok() => {
+                self.rewrite_ipv4(&mut frame, network)
+            }
+            EtherTypes::Ipv6 if validate_ipv6(&frame[network..]).is_ok() => {
+                self.rewrite_ipv6(&mut frame, network)
+            }
+            _ => {}
+        }
+        frame
+    }
+
+    /// Rewrite a pcap or pcapng file into a new pcap file, keeping the timestamps
+    ///
+    /// Returns the number of packets written
+    pub fn rewrite_file(
+        &self,
+        input: impl AsRef<Path>,
+        output: impl AsRef<Path>,
+    ) -> io::Result<usize> {
+        let mut reader = FileSource::new(input)?.open()?;
+        let mut writer = PcapWriter::new(BufWriter::new(File::create(output)?))?;
+        let mut count = 0;
+        while let Some((timestamp, packet)) = reader.next_packet()? {
+            writer.write_packet(timestamp, &self.rewrite_packet(packet))?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    fn rewrite_ipv4(&self, frame: &mut Vec<u8>, network: usize) {
+        let header_length = usize::from(frame[network] & 0x0f) * 4;
+        let total_length =
+            usize::from(u16::from_be_bytes([frame[network + 2], frame[network + 3]]));
+        let protocol = IpNextHeaderProtocol(frame[network + 9]);
+        let addresses = network + 12..network + 20;
+        let original =
+            checksummed_words(frame, addresses.clone(), network + header_length, protocol);
+        for range in [network + 12..network + 16, network + 16..network + 20] {
+            let address = Ipv4Addr::from(<[u8; 4]>::try_from(&frame[range.clone()]).unwrap());
+            if let IpAddr::V4(mapped) = self.map_ip(IpAddr::V4(address)) {
+                frame[range].copy_from_slice(&mapped.octets());
+            }
+        }
+        let flags_and_offset = u16::from_be_bytes([frame[network + 6], frame[network + 7]]);
+        let (transport, end) = (network + header_length, network + total_length);
+        let complete = flags_and_offset & 0x3fff == 0;
+        let trailing_fragment = flags_and_offset & 0x1fff != 0;
+        let length = self.rewrite_transport(
+            &mut frame[transport..end],
+            protocol,
+            trailing_fragment,
+            !complete,
+        );
+        let end = transport + length;
+        if end < network + total_length {
+            frame.truncate(end);
+            frame[network + 2..network + 4]
+                .copy_from_slice(&((end - network) as u16).to_be_bytes());
+        }
+        if !self.fix_checksums {
+            return;
+        }
+        let (source, destination) = (
+            Ipv4Addr::from(<[u8; 4]>::try_from(&frame[network + 12..network + 16]).unwrap()),
+            Ipv4Addr::from(<[u8; 4]>::try_from(&frame[network + 16..network + 20]).unwrap()),
+        );
+        let checksum = util::checksum(&frame[network..network + header_length], 5);
+        frame[network + 10..network + 12].copy_from_slice(&checksum.to_be_bytes());
+        if complete {
+            fix_transport_checksum(
+                &mut frame[transport..end],
+                protocol,
+                PseudoHeader::V4(source, destination),
+            );
+        } else if !trailing_fragment && end == network + total_length {
+            let rewritten = checksummed_words(frame, addresses, transport, protocol);
+            update_fragment_checksum(&mut frame[transport..end], protocol, &original, &rewritten);
+        }
+    }
+
+    fn rewrite_ipv6(&self, frame: &mut Vec<u8>, network: usize) {
+        let Ok(upper) = upper_layer(&frame[network..]) else {
+            return;
+        };
+        let (protocol, trailing_fragment, complete) =
+            (upper.protocol, upper.trailing_fragment, !upper.fragmented);
+        let payload_length =
+            usize::from(u16::from_be_bytes([frame[network + 4], frame[network + 5]]));
+        let end = network + 40 + payload_length;
+        let transport = end - upper.payload.len();
+        let addresses = network + 8..network + 40;
+        let original = checksummed_words(frame, addresses.clone(), transport, protocol);
+        for range in [network + 8..network + 24, network + 24..network + 40] {
+            let address = Ipv6Addr::from(<[u8; 16]>::try_from(&frame[range.clone()]).unwrap());
+            if let IpAddr::V6(mapped) = self.map_ip(IpAddr::V6(address)) {
+                frame[range].copy_from_slice(&mapped.octets());
+            }
+        }
+        let length = self.rewrite_transport(
+            &mut frame[transport..end],
+            protocol,
+            trailing_fragment,
+            !complete,
+        );
+        let new_end = transport + length;
+        if new_end < end {
+            frame.truncate(new_end);
+            frame[network + 4..network + 6]
+                .copy_from_slice(&((new_end - network - 40) as u16).to_be_bytes());
+        }
+        if !self.fix_checksums {
+            return;
+        }
+        if !complete {
+            if !trailing_fragment && new_end == end {
+                let rewritten = checksummed_words(frame, addresses, transport, protocol);
+                update_fragment_checksum(
+                    &mut frame[transport..end],
+                    protocol,
+                    &original,
+                    &rewritten,
+                );
+            }
+            return;
+        }
+        let (source, destination) = (
+            Ipv6Addr::from(<[u8; 16]>::try_from(&frame[network + 8..network + 24]).unwrap()),
+            Ipv6Addr::from(<[u8; 16]>::try_from(&frame[network + 24..network + 40]).unwrap()),
+        );
+        fix_transport_checksum(
+            &mut frame[transport..new_end],
+            protocol,
+            PseudoHeader::V6(source, destination),
+        );
+    }
+
+    /// Map the ports of a TCP or UDP header and truncate the payload
+    ///
+    /// The UDP header of a first fragment only needs to be whole, since the datagram goes on in later fragments.
+    /// Returns how much of the IP payload to keep
+    fn rewrite_transport(
+        &self,
+        transport: &mut [u8],
+        protocol: IpNextHeaderProtocol,
+        trailing_fragment: bool,
+        fragmented: bool,
+    ) -> usize {
+        let header_length = match protocol {
+            _ if trailing_fragment => 0,
+            IpNextHeaderProtocols::Tcp if validate_tcp(transport).is_ok() => {
+                usize::from(transport[12] >> 4) * 4
+            }
+            IpNextHeaderProtocols::Udp
+                if validate_udp(transport).is_ok() || (fragmented && transport.len() >= 8) =>
+            {
+                8
+            }
+            _ => 0,
+        };
+        if header_length > 0 {
+            for port in [0..2, 2..4] {
+                let number = u16::from_be_bytes([transport[port.start], transport[port.start + 1]]);
+                if let Some(mapped) = self.port_maps.get(&number) {
+                    transport[port].copy_from_slice(&mapped.to_be_bytes());
+                }
+            }
+        }
+        let Some(limit) = self.truncate else {
+            return transport.len();
+        };
+        let length = transport.len().min(header_length + limit);
+        if protocol == IpNextHeaderProtocols::Udp && header_length > 0 {
+            transport[4..6].copy_from_slice(&(length as u16).to_be_bytes());
+        }
+        length
+    }
+
+    fn map_ip(&self, address: IpAddr) -> IpAddr {
+        let Some((from, to)) = self.ip_maps.iter().find(|(from, _)| from.contains(address)) else {
+            return address;
+        };
+        match (address, from, to) {
+            (IpAddr::V4(address), IpNetwork::V4(from), IpNetwork::V4(to)) => {
+                let host = u32::from(address) & !u32::from(from.mask());
+                IpAddr::V4(Ipv4Addr::from(
+                    u32::from(to.network()) | (host & !u32::from(to.mask())),
+                ))
+            }
+            (IpAddr::V6(address), IpNetwork::V6(from), IpNetwork::V6(to)) => {
+                let host = u128::from(address) & !u128::from(from.mask());
+                IpAddr::V6(Ipv6Addr::from(
+                    u128::from(to.network()) | (host & !u128::from(to.mask())),
+                ))
+            }
+            _ => address,
+        }
+    }
+}
+
+fn same_family(from: IpNetwork, to: IpNetwork) -> bool {
+    from.is_ipv4() == to.is_ipv4()
+}
+
+/// Copy the words a transport checksum covers that rewriting may change: the addresses, then the TCP or UDP ports
+fn checksummed_words(
+    frame: &[u8],
+    addresses: Range<usize>,
+    transport: usize,
+    protocol: IpNextHeaderProtocol,
+) -> Vec<u8> {
+    let mut words = frame[addresses].to_vec();
+    if matches!(
+        protocol,
+        IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp
+    ) {
+        words.extend_from_slice(frame.get(transport..transport + 4).unwrap_or_default());
+    }
+    words
+}
+
+/// Carry rewritten addresses and ports over to the checksum in the first fragment of a TCP, UDP or ICMPv6 message
+///
+/// The checksum covers data held by later fragments, so it is updated for the changed words rather than recomputed
+fn update_fragment_checksum(
+    transport: &mut [u8],
+    protocol: IpNextHeaderProtocol,
+    original: &[u8],
+    rewritten: &[u8],
+) {
+    let offset = match protocol {
+        IpNextHeaderProtocols::Tcp => 16,
+        IpNextHeaderProtocols::Udp => 6,
+        IpNextHeaderProtocols::Icmpv6 => 2,
+        _ => return,
+    };
+    let Some(field) = transport.get_mut(offset..offset + 2) else {
+        return;
+    };
+    let checksum = u16::from_be_bytes([field[0], field[1]]);
+    // A UDP checksum of zero means none was computed
+    if protocol == IpNextHeaderProtocols::Udp && checksum == 0 {
+        return;
+    }
+    let checksum = match update_checksum(checksum, original, rewritten) {
+        0 if protocol == IpNextHeaderProtocols::Udp => 0xffff,
+        checksum => checksum,
+    };
+    field.copy_from_slice(&checksum.to_be_bytes());
+}
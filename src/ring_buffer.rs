@@ -0,0 +1,118 @@
+use crate::PacketStore;
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+/// Amount of traffic a ring capture keeps on one side of a trigger
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RingWindow {
+    /// A number of packets
+    Packets(usize),
+    /// A span of time
+    Duration(Duration),
+}
+
+/// Packets gathered since a trigger matched
+#[derive(Debug)]
+struct TriggeredEvent {
+    triggered_at: SystemTime,
+    packets_after: usize,
+    store: PacketStore,
+}
+
+/// Ring of the most recent packets, which turns into an event when a trigger matches
+#[derive(Debug)]
+pub(crate) struct TriggerRing {
+    before: RingWindow,
+    after: RingWindow,
+    ring: VecDeque<(SystemTime, Vec<u8>)>,
+    event: Option<TriggeredEvent>,
+}
+
+impl TriggerRing {
+    pub(crate) fn new(before: RingWindow, after: RingWindow) -> TriggerRing {
+        TriggerRing {
+            before,
+            after,
+            ring: VecDeque::new(),
+            event: None,
+        }
+    }
+
+    /// Add a packet to the ring
+    ///
+    /// The trigger is only evaluated while no event is in progress.
+    /// Returns the packets of an event once its window after the trigger is complete
+    pub(crate) fn push(
+        &mut self,
+        timestamp: SystemTime,
+        packet: &[u8],
+        triggered: impl FnOnce() -> bool,
+    ) -> Option<PacketStore> {
+        match self.event.as_mut() {
+            Some(event) => {
+                event.store.push(packet, timestamp);
+                event.packets_after += 1;
+            }
+            None if triggered() => {
+                let mut store = PacketStore::preallocated();
+                for (ring_timestamp, ring_packet) in self.ring.iter() {
+                    store.push(ring_packet, *ring_timestamp);
+                }
+                store.push(packet, timestamp);
+                self.event = Some(TriggeredEvent {
+                    triggered_at: timestamp,
+                    packets_after: 0,
+                    store,
+                });
+            }
+            None => {}
+        }
+
+        // Reuse the buffer of the packet falling out of the ring when there is one
+        let mut buffer = match self.before {
+            RingWindow::Packets(count) if self.ring.len() >= count => {
+                self.ring.pop_front().map(|(_, buffer)| buffer)
+            }
+            _ => None,
+        }
+        .unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(packet);
+        self.ring.push_back((timestamp, buffer));
+        match self.before {
+            RingWindow::Packets(count) => {
+                while self.ring.len() > count {
+                    self.ring.pop_front();
+                }
+            }
+            RingWindow::Duration(duration) => {
+                while self
+                    .ring
+                    .front()
+                    .is_some_and(|(ring_timestamp, _)| *ring_timestamp + duration < timestamp)
+                {
+                    self.ring.pop_front();
+                }
+            }
+        }
+
+        self.complete(timestamp)
+    }
+
+    /// Return the packets of an event whose window after the trigger has run out of time
+    pub(crate) fn complete(&mut self, now: SystemTime) -> Option<PacketStore> {
+        let complete = self.event.as_ref().is_some_and(|event| match self.after {
+            RingWindow::Packets(count) => event.packets_after >= count,
+            RingWindow::Duration(duration) => now >= event.triggered_at + duration,
+        });
+        match complete {
+            true => self.finish(),
+            false => None,
+        }
+    }
+
+    /// Return the packets of the event in progress, even if its window after the trigger isn't complete
+    pub(crate) fn finish(&mut self) -> Option<PacketStore> {
+        self.event.take().map(|event| event.store)
+    }
+}
@@ -0,0 +1,227 @@
+use crate::pcap::{PcapWriter, PcapngWriter, PCAP_SNAPLEN};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Format of the files written by a RotatingWriter
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileFormat {
+    /// Classic libpcap format
+    #[default]
+    Pcap,
+    /// pcapng format
+    Pcapng,
+}
+
+/// When a RotatingWriter moves on to a new file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// Once the file would grow past a number of bytes
+    Size(u64),
+    /// Once a file has been open for a span of time
+    Time(Duration),
+    /// Once the file holds a number of packets
+    Packets(usize),
+}
+
+/// Hook called with the path of each completed file
+type CloseHook = Box<dyn FnMut(&Path) + Send>;
+
+/// File currently being written to
+enum FileWriter {
+    Pcap(PcapWriter<BufWriter<File>>),
+    Pcapng(PcapngWriter<BufWriter<File>>),
+}
+
+impl FileWriter {
+    fn create(path: &Path, format: FileFormat) -> io::Result<FileWriter> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(match format {
+            FileFormat::Pcap => FileWriter::Pcap(PcapWriter::new(file)?),
+            FileFormat::Pcapng => FileWriter::Pcapng(PcapngWriter::new(file)?),
+        })
+    }
+
+    fn write_packet(&mut self, timestamp: SystemTime, packet: &[u8]) -> io::Result<()> {
+        match self {
+            FileWriter::Pcap(writer) => writer.write_packet(timestamp, packet),
+            FileWriter::Pcapng(writer) => writer.write_packet(timestamp, packet),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            FileWriter::Pcap(writer) => writer.flush(),
+            FileWriter::Pcapng(writer) => writer.flush(),
+        }
+    }
+}
+
+/// File currently being written to, along with what is needed to decide when to rotate it
+struct OpenFile {
+    path: PathBuf,
+    writer: FileWriter,
+    opened_at: SystemTime,
+    bytes: u64,
+    packets: usize,
+}
+
+/// Writes packets to a series of pcap or pcapng files, like `tcpdump -C/-G/-W`
+///
+/// File names come from a template, in which `{index}` is replaced by the number of the file and
+/// `{timestamp}` by the time its first packet was captured, in seconds since the epoch.
+/// A template without `{index}` gets the number of the file appended to it, so that files opened within the same
+/// second don't share a name.
+/// Files are created when their first packet is written
+pub struct RotatingWriter {
+    template: String,
+    format: FileFormat,
+    rotation: Rotation,
+    max_files: Option<usize>,
+    on_close: Option<CloseHook>,
+    current: Option<OpenFile>,
+    closed: VecDeque<PathBuf>,
+    index: usize,
+}
+
+impl fmt::Debug for RotatingWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotatingWriter")
+            .field("template", &self.template)
+            .field("format", &self.format)
+            .field("rotation", &self.rotation)
+            .field("max_files", &self.max_files)
+            .field("current", &self.current.as_ref().map(|file| &file.path))
+            .field("closed", &self.closed)
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
+impl RotatingWriter {
+    /// Create a RotatingWriter
+    pub fn new(template: &str, format: FileFormat, rotation: Rotation) -> RotatingWriter {
+        RotatingWriter {
+            template: template.to_string(),
+            format,
+            rotation,
+            max_files: None,
+            on_close: None,
+            current: None,
+            closed: VecDeque::new(),
+            index: 0,
+        }
+    }
+
+    /// Keep at most `max_files` files on disk, removing the oldest ones as new files are created
+    pub fn set_max_files(&mut self, max_files: usize) {
+        self.max_files = Some(max_files.max(1));
+    }
+
+    /// Call `on_close` with the path of each file once it is complete
+    pub fn set_on_close(&mut self, on_close: impl FnMut(&Path) + Send + 'static) {
+        self.on_close = Some(Box::new(on_close));
+    }
+
+    /// Write a packet along with the time it was captured, moving on to a new file first if needed
+    pub fn write_packet(&mut self, timestamp: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let record_length = self.record_length(packet);
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|file| self.is_full(file, timestamp, record_length))
+        {
+            self.close()?;
+        }
+        let file = match self.current.as_mut() {
+            Some(file) => file,
+            None => {
+                let file = self.open(timestamp)?;
+                self.current.insert(file)
+            }
+        };
+        file.writer.write_packet(timestamp, packet)?;
+        file.bytes += record_length;
+        file.packets += 1;
+        Ok(())
+    }
+
+    /// Complete the current file
+    ///
+    /// The next packet written starts a new file
+    pub fn close(&mut self) -> io::Result<()> {
+        let Some(mut file) = self.current.take() else {
+            return Ok(());
+        };
+        file.writer.flush()?;
+        drop(file.writer);
+        if let Some(on_close) = self.on_close.as_mut() {
+            on_close(&file.path);
+        }
+        self.closed.push_back(file.path);
+        Ok(())
+    }
+
+    fn is_full(&self, file: &OpenFile, timestamp: SystemTime, record_length: u64) -> bool {
+        match self.rotation {
+            Rotation::Size(size) => file.packets > 0 && file.bytes + record_length > size,
+            Rotation::Time(duration) => timestamp >= file.opened_at + duration,
+            Rotation::Packets(count) => file.packets >= count,
+        }
+    }
+
+    fn open(&mut self, timestamp: SystemTime) -> io::Result<OpenFile> {
+        if let Some(max_files) = self.max_files {
+            while self.closed.len() >= max_files {
+                // The close hook may already have moved the file elsewhere
+                match self.closed.pop_front().map(fs::remove_file) {
+                    Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        let path = PathBuf::from(self.file_name(timestamp));
+        let writer = FileWriter::create(&path, self.format)?;
+        self.index += 1;
+        let bytes = match self.format {
+            FileFormat::Pcap => 24,
+            FileFormat::Pcapng => 48,
+        };
+        Ok(OpenFile {
+            path,
+            writer,
+            opened_at: timestamp,
+            bytes,
+            packets: 0,
+        })
+    }
+
+    fn file_name(&self, timestamp: SystemTime) -> String {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let name = self
+            .template
+            .replace("{timestamp}", &since_epoch.as_secs().to_string());
+        match self.template.contains("{index}") {
+            true => name.replace("{index}", &self.index.to_string()),
+            false => format!("{name}{}", self.index),
+        }
+    }
+
+    /// Number of bytes a packet takes up in the file
+    fn record_length(&self, packet: &[u8]) -> u64 {
+        let captured_length = packet.len().min(PCAP_SNAPLEN as usize) as u64;
+        match self.format {
+            FileFormat::Pcap => 16 + captured_length,
+            FileFormat::Pcapng => 32 + captured_length.next_multiple_of(4),
+        }
+    }
+}
+
+impl Drop for RotatingWriter {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
@@ -0,0 +1,408 @@
+#[cfg(target_os = "linux")]
+use crate::packet_socket::PacketSocket;
+use crate::pcap::{PcapReader, PcapngReader, LINKTYPE_ETHERNET, PCAP_SNAPLEN};
+use pnet::datalink::NetworkInterface;
+#[cfg(not(target_os = "linux"))]
+use pnet::datalink::{self, Channel::Ethernet, DataLinkReceiver};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::sync::{Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a live reader waits for a packet before giving the capture thread a chance to check whether it should stop
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How far a replay may fall behind before it stops catching up and carries on from where it is, such as after a pause
+const MAX_REPLAY_LAG: Duration = Duration::from_secs(1);
+
+/// Magic number opening pcapng files
+const PCAPNG_MAGIC: [u8; 4] = [0x0a, 0x0d, 0x0d, 0x0a];
+
+/// Somewhere a PacketCapture can read raw ethernet frames from
+pub trait PacketSource: fmt::Debug + Send + Sync {
+    /// Name of the source, such as an interface name or a file path
+    fn name(&self) -> String;
+
+    /// Start reading packets
+    fn open(&self) -> io::Result<Box<dyn PacketReader>>;
+
+    /// Number of packets the source has dropped so far, if it keeps count
+    fn dropped_packets(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Packets being read from a PacketSource
+pub trait PacketReader: Send {
+    /// Read the next packet along with the time it was captured
+    ///
+    /// Returns `Ok(None)` once the source has no more packets, and an error of kind `TimedOut`
+    /// when no packet arrived in time but more may still come
+    fn next_packet(&mut self) -> io::Result<Option<(SystemTime, &[u8])>>;
+
+    /// Return true if packets arrive whether they are read or not, so they should be read and dropped while paused
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+/// Packets captured live from a network interface
+///
+/// On Linux the packets the kernel drops on the capture socket are counted, from its `PACKET_STATISTICS`
+#[derive(Clone, Debug)]
+pub struct LiveSource {
+    interface: NetworkInterface,
+    /// Packets the kernel dropped on the sockets opened so far
+    #[cfg(target_os = "linux")]
+    drops: Arc<AtomicU64>,
+    /// Socket currently capturing, so its drops can be read while it's open
+    #[cfg(target_os = "linux")]
+    socket: Arc<Mutex<Weak<PacketSocket>>>,
+}
+
+impl LiveSource {
+    /// Create a LiveSource on an interface
+    pub fn new(interface: NetworkInterface) -> LiveSource {
+        LiveSource {
+            interface,
+            #[cfg(target_os = "linux")]
+            drops: Arc::default(),
+            #[cfg(target_os = "linux")]
+            socket: Arc::default(),
+        }
+    }
+}
+
+impl PacketSource for LiveSource {
+    fn name(&self) -> String {
+        self.interface.name.clone()
+    }
+
+    /// Open a packet socket on the interface that gives up on reads often enough to notice a stop
+    #[cfg(target_os = "linux")]
+    fn open(&self) -> io::Result<Box<dyn PacketReader>> {
+        let socket = Arc::new(PacketSocket::open(
+            &self.interface,
+            READ_TIMEOUT,
+            Arc::clone(&self.drops),
+        )?);
+        *self.socket.lock().unwrap() = Arc::downgrade(&socket);
+        Ok(Box::new(SocketReader {
+            socket,
+            buffer: vec![0; PCAP_SNAPLEN as usize],
+        }))
+    }
+
+    /// Read the `PACKET_STATISTICS` of the capture socket, which the kernel counts from when the socket was opened
+    #[cfg(target_os = "linux")]
+    fn dropped_packets(&self) -> Option<u64> {
+        if let Some(socket) = self.socket.lock().unwrap().upgrade() {
+            socket.count_drops();
+        }
+        Some(self.drops.load(Ordering::Relaxed))
+    }
+
+    /// Open a channel on the interface that gives up on reads often enough to notice a stop
+    #[cfg(not(target_os = "linux"))]
+    fn open(&self) -> io::Result<Box<dyn PacketReader>> {
+        let config = datalink::Config {
+            read_timeout: Some(READ_TIMEOUT),
+            ..Default::default()
+        };
+        match datalink::channel(&self.interface, config)? {
+            Ethernet(_, rx) => Ok(Box::new(LiveReader(rx))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Non-ethernet channel created",
+            )),
+        }
+    }
+}
+
+/// Frames received on a packet socket, along with room for the last one
+#[cfg(target_os = "linux")]
+struct SocketReader {
+    socket: Arc<PacketSocket>,
+    buffer: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl PacketReader for SocketReader {
+    fn next_packet(&mut self) -> io::Result<Option<(SystemTime, &[u8])>> {
+        let length = self.socket.recv(&mut self.buffer)?;
+        Ok(Some((SystemTime::now(), &self.buffer[..length])))
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct LiveReader(Box<dyn DataLinkReceiver>);
+
+#[cfg(not(target_os = "linux"))]
+impl PacketReader for LiveReader {
+    fn next_packet(&mut self) -> io::Result<Option<(SystemTime, &[u8])>> {
+        self.0
+            .next()
+            .map(|packet| Some((SystemTime::now(), packet)))
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+/// Packets read from a pcap or pcapng file, with the timestamps recorded in the file
+#[derive(Clone, Debug)]
+pub struct FileSource {
+    path: PathBuf,
+}
+
+impl FileSource {
+    /// Create a FileSource
+    ///
+    /// Checks right away that the file can be opened and holds ethernet frames
+    pub fn new(path: impl AsRef<Path>) -> io::Result<FileSource> {
+        let source = FileSource {
+            path: path.as_ref().to_path_buf(),
+        };
+        source.open_file()?;
+        Ok(source)
+    }
+
+    fn open_file(&self) -> io::Result<FileReader> {
+        let mut file = BufReader::new(File::open(&self.path)?);
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        let file = Cursor::new(magic).chain(file);
+        if magic == PCAPNG_MAGIC {
+            return Ok(FileReader::Pcapng(PcapngReader::new(file)?, vec![]));
+        }
+        let reader = PcapReader::new(file)?;
+        if reader.link_type() != LINKTYPE_ETHERNET {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Unsupported link type {} in {}",
+                    reader.link_type(),
+                    self.path.display()
+                ),
+            ));
+        }
+        Ok(FileReader::Pcap(reader, vec![]))
+    }
+}
+
+impl PacketSource for FileSource {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn open(&self) -> io::Result<Box<dyn PacketReader>> {
+        Ok(Box::new(self.open_file()?))
+    }
+}
+
+type CaptureFile = io::Chain<Cursor<[u8; 4]>, BufReader<File>>;
+
+/// Reader of either capture file format, along with the last packet read
+enum FileReader {
+    Pcap(PcapReader<CaptureFile>, Vec<u8>),
+    Pcapng(PcapngReader<CaptureFile>, Vec<u8>),
+}
+
+impl PacketReader for FileReader {
+    fn next_packet(&mut self) -> io::Result<Option<(SystemTime, &[u8])>> {
+        let (next, packet) = match self {
+            FileReader::Pcap(reader, packet) => (reader.next_packet()?, packet),
+            FileReader::Pcapng(reader, packet) => (reader.next_packet()?, packet),
+        };
+        Ok(next.map(|(timestamp, next_packet)| {
+            *packet = next_packet;
+            (timestamp, packet.as_slice())
+        }))
+    }
+}
+
+/// Packets held in memory
+///
+/// Packets given without a timestamp are stamped with the time they are read
+#[derive(Clone, Debug)]
+pub struct MemorySource {
+    packets: Arc<[(Option<SystemTime>, Vec<u8>)]>,
+}
+
+impl MemorySource {
+    /// Create a MemorySource from raw ethernet frames
+    pub fn new(packets: Vec<Vec<u8>>) -> MemorySource {
+        MemorySource {
+            packets: packets.into_iter().map(|packet| (None, packet)).collect(),
+        }
+    }
+
+    /// Create a MemorySource from raw ethernet frames along with the time they were captured
+    pub fn with_timestamps(packets: Vec<(SystemTime, Vec<u8>)>) -> MemorySource {
+        MemorySource {
+            packets: packets
+                .into_iter()
+                .map(|(timestamp, packet)| (Some(timestamp), packet))
+                .collect(),
+        }
+    }
+}
+
+impl PacketSource for MemorySource {
+    fn name(&self) -> String {
+        String::from("memory")
+    }
+
+    fn open(&self) -> io::Result<Box<dyn PacketReader>> {
+        Ok(Box::new(MemoryReader {
+            packets: Arc::clone(&self.packets),
+            next: 0,
+        }))
+    }
+}
+
+struct MemoryReader {
+    packets: Arc<[(Option<SystemTime>, Vec<u8>)]>,
+    next: usize,
+}
+
+impl PacketReader for MemoryReader {
+    fn next_packet(&mut self) -> io::Result<Option<(SystemTime, &[u8])>> {
+        let Some((timestamp, packet)) = self.packets.get(self.next) else {
+            return Ok(None);
+        };
+        self.next += 1;
+        Ok(Some((timestamp.unwrap_or_else(SystemTime::now), packet)))
+    }
+}
+
+/// How fast a ReplaySource plays back its packets
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReplaySpeed {
+    /// With the gaps between packets they were captured with
+    #[default]
+    Original,
+    /// With the original gaps divided by a factor, so `Scaled(2.0)` plays twice as fast
+    Scaled(f64),
+    /// As fast as the packets can be read
+    Unlimited,
+}
+
+/// Packets from another source played back as though they were arriving live
+///
+/// Packets are held back to reproduce the gaps between their timestamps,
+/// and are stamped with the time they are played back rather than the time they were captured
+#[derive(Debug)]
+pub struct ReplaySource {
+    source: Box<dyn PacketSource>,
+    speed: ReplaySpeed,
+}
+
+impl ReplaySource {
+    /// Create a ReplaySource playing back another source, usually a FileSource
+    ///
+    /// Fails with `InvalidInput` if a `ReplaySpeed::Scaled` factor isn't a positive number
+    pub fn new(
+        source: impl PacketSource + 'static,
+        speed: ReplaySpeed,
+    ) -> io::Result<ReplaySource> {
+        if let ReplaySpeed::Scaled(factor) = speed {
+            if !(factor.is_finite() && factor > 0.0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Replay speed factor must be positive, got {factor}"),
+                ));
+            }
+        }
+        Ok(ReplaySource {
+            source: Box::new(source),
+            speed,
+        })
+    }
+}
+
+impl PacketSource for ReplaySource {
+    fn name(&self) -> String {
+        format!("replay of {}", self.source.name())
+    }
+
+    fn open(&self) -> io::Result<Box<dyn PacketReader>> {
+        Ok(Box::new(ReplayReader {
+            reader: self.source.open()?,
+            speed: self.speed,
+            anchor: None,
+            pending: None,
+            packet: vec![],
+        }))
+    }
+}
+
+struct ReplayReader {
+    reader: Box<dyn PacketReader>,
+    speed: ReplaySpeed,
+    /// When a packet was played back, along with its original timestamp
+    anchor: Option<(Instant, SystemTime)>,
+    /// Original timestamp of the packet waiting to be played back
+    pending: Option<SystemTime>,
+    packet: Vec<u8>,
+}
+
+impl ReplayReader {
+    /// When the pending packet is due, or None if it should be played back right away
+    fn due(&self, timestamp: SystemTime) -> Option<Instant> {
+        let factor = match self.speed {
+            ReplaySpeed::Original => 1.0,
+            ReplaySpeed::Scaled(factor) => factor,
+            ReplaySpeed::Unlimited => return None,
+        };
+        let (played_at, anchor_timestamp) = self.anchor?;
+        let gap = timestamp
+            .duration_since(anchor_timestamp)
+            .unwrap_or_default();
+        let due = played_at + gap.div_f64(factor);
+        // Too far behind to catch up by bursting
+        (Instant::now() <= due + MAX_REPLAY_LAG).then_some(due)
+    }
+}
+
+impl PacketReader for ReplayReader {
+    /// Sleep until the next packet is due, giving up with `TimedOut` on long gaps so stops are noticed
+    fn next_packet(&mut self) -> io::Result<Option<(SystemTime, &[u8])>> {
+        let timestamp = match self.pending {
+            Some(timestamp) => timestamp,
+            None => {
+                let Some((timestamp, packet)) = self.reader.next_packet()? else {
+                    return Ok(None);
+                };
+                self.packet.clear();
+                self.packet.extend_from_slice(packet);
+                *self.pending.insert(timestamp)
+            }
+        };
+        match self.due(timestamp) {
+            Some(due) => {
+                let wait = due.saturating_duration_since(Instant::now());
+                if wait > READ_TIMEOUT {
+                    thread::sleep(READ_TIMEOUT);
+                    return Err(io::Error::from(io::ErrorKind::TimedOut));
+                }
+                thread::sleep(wait);
+            }
+            None => self.anchor = Some((Instant::now(), timestamp)),
+        }
+        self.pending = None;
+        Ok(Some((SystemTime::now(), &self.packet)))
+    }
+}
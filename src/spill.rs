@@ -0,0 +1,165 @@
+use crate::pcap::{PcapWriter, PCAP_SNAPLEN};
+use crate::PacketStore;
+use memmap2::{Mmap, MmapOptions};
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufWriter};
+use std::mem;
+use std::ops::Deref;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Tell spill files of the same process apart
+static SPILL_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How many names are tried before giving up on creating a spill file
+const SPILL_FILE_ATTEMPTS: usize = 16;
+
+/// Length of the pcap record header written in front of every spilled packet
+const RECORD_HEADER_LENGTH: usize = 16;
+
+/// Build a spill file name that other users of the temporary directory can't guess ahead of time
+fn spill_file_name() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(process::id());
+    hasher.write_usize(SPILL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("wiretap-{:016x}.pcap", hasher.finish())
+}
+
+/// Create a new file only the current user can read, failing rather than following a file or link already there
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+/// Path of a spill file, which is removed once nothing refers to the file anymore
+#[derive(Debug)]
+struct SpillPath(PathBuf);
+
+impl Drop for SpillPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Part of a spill file mapped into memory, which the operating system pages in as packets are read
+#[derive(Debug)]
+pub(crate) struct MappedSpill {
+    map: Mmap,
+    // Dropped after the map, so the file is unmapped before it is removed
+    _path: Arc<SpillPath>,
+}
+
+impl Deref for MappedSpill {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+/// Temporary pcap file holding the packets that didn't fit under the memory limit
+///
+/// Spilled packets are served straight from the file rather than read back, so only their index stays in memory.
+/// The file is removed once the SpillFile and every PacketStore serving its packets are dropped
+#[derive(Debug)]
+pub(crate) struct SpillFile {
+    path: Arc<SpillPath>,
+    file: File,
+    writer: PcapWriter<BufWriter<File>>,
+    /// Bytes written to the file so far
+    written: usize,
+    /// Bytes at the start of the file that are already mapped
+    mapped_length: usize,
+    /// When and where in the file each packet written since the last mapping sits
+    unmapped: Vec<(SystemTime, usize, usize)>,
+    /// Packets of the mapped part of the file
+    mapped: PacketStore,
+}
+
+impl SpillFile {
+    /// Create a spill file under a random name in the temporary directory
+    pub(crate) fn create() -> io::Result<SpillFile> {
+        let mut attempts = 0;
+        let (path, file) = loop {
+            let path = std::env::temp_dir().join(spill_file_name());
+            match create_private(&path) {
+                Ok(file) => break (path, file),
+                Err(e)
+                    if e.kind() == io::ErrorKind::AlreadyExists
+                        && attempts < SPILL_FILE_ATTEMPTS =>
+                {
+                    attempts += 1
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let path = Arc::new(SpillPath(path));
+        let writer = PcapWriter::new(BufWriter::new(file.try_clone()?))?;
+        Ok(SpillFile {
+            path,
+            file,
+            writer,
+            written: 24,
+            mapped_length: 0,
+            unmapped: vec![],
+            mapped: PacketStore::default(),
+        })
+    }
+
+    /// Append every packet of a store to the file
+    pub(crate) fn spill(&mut self, store: &PacketStore) -> io::Result<()> {
+        for (timestamp, packet) in store.iter_with_timestamps() {
+            self.writer.write_packet(timestamp, packet)?;
+            let length = packet.len().min(PCAP_SNAPLEN as usize);
+            self.unmapped
+                .push((timestamp, self.written + RECORD_HEADER_LENGTH, length));
+            self.written += RECORD_HEADER_LENGTH + length;
+        }
+        Ok(())
+    }
+
+    /// Get a PacketStore serving every spilled packet from the file
+    ///
+    /// Maps the packets spilled since the last call, so the file can still be spilled to afterwards
+    pub(crate) fn packets(&mut self) -> io::Result<PacketStore> {
+        self.writer.flush()?;
+        if !self.unmapped.is_empty() {
+            // Safety: the file is private to this process and only ever appended to, so mapped bytes never change
+            let map = unsafe {
+                MmapOptions::new()
+                    .offset(self.mapped_length as u64)
+                    .len(self.written - self.mapped_length)
+                    .map(&self.file)?
+            };
+            let mapped_spill = Arc::new(MappedSpill {
+                map,
+                _path: Arc::clone(&self.path),
+            });
+            let start = self.mapped_length;
+            self.mapped.push_mapped(
+                mapped_spill,
+                self.unmapped
+                    .drain(..)
+                    .map(|(timestamp, offset, length)| (timestamp, offset - start, length)),
+            );
+            self.mapped_length = self.written;
+        }
+        Ok(self.mapped.clone())
+    }
+
+    /// Get a PacketStore serving every spilled packet from the file, giving up the file
+    pub(crate) fn into_packets(mut self) -> io::Result<PacketStore> {
+        self.packets()?;
+        Ok(mem::take(&mut self.mapped))
+    }
+}
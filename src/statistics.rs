@@ -0,0 +1,154 @@
+use crate::ProtocolLayer;
+use std::ops::Add;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Number of packets that could not be dissected, by the layer they failed at
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseFailures {
+    pub link: u64,
+    pub network: u64,
+    pub transport: u64,
+    pub application: u64,
+}
+
+impl ParseFailures {
+    /// Number of packets that failed at a layer
+    pub fn get(&self, layer: ProtocolLayer) -> u64 {
+        match layer {
+            ProtocolLayer::Link => self.link,
+            ProtocolLayer::Network => self.network,
+            ProtocolLayer::Transport => self.transport,
+            ProtocolLayer::Application => self.application,
+        }
+    }
+
+    /// Number of packets that failed at any layer
+    pub fn total(&self) -> u64 {
+        self.link + self.network + self.transport + self.application
+    }
+
+    pub(crate) fn count(&mut self, layer: ProtocolLayer) {
+        match layer {
+            ProtocolLayer::Link => self.link += 1,
+            ProtocolLayer::Network => self.network += 1,
+            ProtocolLayer::Transport => self.transport += 1,
+            ProtocolLayer::Application => self.application += 1,
+        }
+    }
+}
+
+impl Add for ParseFailures {
+    type Output = ParseFailures;
+
+    fn add(self, other: ParseFailures) -> ParseFailures {
+        ParseFailures {
+            link: self.link + other.link,
+            network: self.network + other.network,
+            transport: self.transport + other.transport,
+            application: self.application + other.application,
+        }
+    }
+}
+
+/// How much traffic a capture saw, and how much of it was lost
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CaptureStatistics {
+    /// Packets read from the source while not paused
+    pub packets_received: u64,
+    /// Bytes of the packets read from the source while not paused
+    pub bytes_received: u64,
+    /// Packets the kernel dropped since the capture started because the capture socket fell behind, if the source keeps count
+    ///
+    /// Live sources on Linux read it from the `PACKET_STATISTICS` of their socket
+    pub kernel_drops: Option<u64>,
+    /// Packets wiretap dropped because a queue towards a consumer was full
+    ///
+    /// Captures that store every packet or hand them over synchronously never drop any
+    pub buffer_drops: u64,
+    /// Packets that could not be dissected
+    ///
+    /// Counted while capturing by the live processing modes that decode packets,
+    /// and for completed captures from the packets in their results
+    pub parse_failures: ParseFailures,
+    /// Time since the capture started, up to when it stopped
+    pub duration: Duration,
+}
+
+/// How much traffic a PacketInjector sent during a replay
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SendStatistics {
+    /// Frames the interface accepted
+    pub packets_sent: u64,
+    /// Bytes of the frames the interface accepted
+    pub bytes_sent: u64,
+    /// Frames the interface refused, such as ones larger than its MTU
+    pub send_failures: u64,
+    /// Time the replay took
+    pub duration: Duration,
+}
+
+/// Counters updated by the capture thread and read by the PacketCapture
+#[derive(Debug, Default)]
+pub(crate) struct CaptureCounters {
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+    buffer_drops: AtomicU64,
+    parse_failures: [AtomicU64; 4],
+    started: OnceLock<(Instant, Option<u64>)>,
+    stopped: OnceLock<(Instant, Option<u64>)>,
+}
+
+impl CaptureCounters {
+    pub(crate) fn start(&self, dropped_packets: Option<u64>) {
+        let _ = self.started.set((Instant::now(), dropped_packets));
+    }
+
+    pub(crate) fn stop(&self, dropped_packets: Option<u64>) {
+        let _ = self.stopped.set((Instant::now(), dropped_packets));
+    }
+
+    pub(crate) fn record_packet(&self, length: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(length as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_buffer_drop(&self) {
+        self.buffer_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_parse_failure(&self, layer: ProtocolLayer) {
+        self.parse_failures[layer as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Read the counters, along with the source drops and duration up to now or up to the stop
+    pub(crate) fn statistics(&self, dropped_packets: Option<u64>) -> CaptureStatistics {
+        let parse_failures =
+            |layer: ProtocolLayer| self.parse_failures[layer as usize].load(Ordering::Relaxed);
+        let (duration, kernel_drops) = match (self.started.get(), self.stopped.get()) {
+            (Some((started_at, drops_at_start)), Some((stopped_at, drops_at_stop))) => (
+                stopped_at.duration_since(*started_at),
+                drops_at_start.zip(*drops_at_stop),
+            ),
+            (Some((started_at, drops_at_start)), None) => {
+                (started_at.elapsed(), drops_at_start.zip(dropped_packets))
+            }
+            (None, _) => (Duration::ZERO, None),
+        };
+        CaptureStatistics {
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            kernel_drops: kernel_drops.map(|(start, now)| now.saturating_sub(start)),
+            buffer_drops: self.buffer_drops.load(Ordering::Relaxed),
+            parse_failures: ParseFailures {
+                link: parse_failures(ProtocolLayer::Link),
+                network: parse_failures(ProtocolLayer::Network),
+                transport: parse_failures(ProtocolLayer::Transport),
+                application: parse_failures(ProtocolLayer::Application),
+            },
+            duration,
+        }
+    }
+}
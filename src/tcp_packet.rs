@@ -1,15 +1,22 @@
+use crate::checksum::{transport_status, ChecksumStatus, PseudoHeader};
+use crate::dissection::{ipv4_to_tcp, validate_tcp, Malformation};
+use crate::PacketCollection;
+use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::tcp::TcpPacket as pnet_TcpPacket;
 use pnet::packet::Packet;
+use rayon::prelude::*;
+use std::fmt;
+use std::net::IpAddr;
 use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
 
 /// Wrapper around pnet's TcpPacket for adding additional funcitonality
-#[derive(Debug)]
-pub struct TcpSegment<'a>(pnet_TcpPacket<'a>);
+///
+/// A wrapper created from a byte slice remembers it, so cloning the wrapper doesn't copy the packet
+pub struct TcpSegment<'a>(pnet_TcpPacket<'a>, Option<&'a [u8]>);
 
 impl<'a> From<pnet_TcpPacket<'a>> for TcpSegment<'a> {
     fn from(ipv4_packet: pnet_TcpPacket<'a>) -> Self {
-        TcpSegment(ipv4_packet)
+        TcpSegment(ipv4_packet, None)
     }
 }
 
@@ -21,9 +28,37 @@ impl<'a> Deref for TcpSegment<'a> {
     }
 }
 
+impl Clone for TcpSegment<'_> {
+    fn clone(&self) -> Self {
+        match self.1 {
+            Some(packet) => TcpSegment::borrowed(packet),
+            None => self.create_clone(),
+        }
+    }
+}
+
+impl fmt::Debug for TcpSegment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TcpSegment").field(&self.0).finish()
+    }
+}
+
+impl<'a> TcpSegment<'a> {
+    /// Wrap bytes already known to hold the header, without copying them
+    pub(crate) fn borrowed(packet: &'a [u8]) -> TcpSegment<'a> {
+        TcpSegment(pnet_TcpPacket::new(packet).unwrap(), Some(packet))
+    }
+}
+
 impl TcpSegment<'_> {
     pub fn new<'a>(packet: &'a [u8]) -> Option<TcpSegment<'a>>{
-        pnet_TcpPacket::new(packet).map(TcpSegment::from)
+        TcpSegment::try_new(packet).ok()
+    }
+
+    /// Create a TcpSegment after checking the data offset of the header
+    pub fn try_new(packet: &[u8]) -> Result<TcpSegment<'_>, Malformation> {
+        validate_tcp(packet)?;
+        Ok(TcpSegment::borrowed(packet))
     }
 
     /// Return true if the TCP segment has a payload
@@ -35,6 +70,19 @@ impl TcpSegment<'_> {
         TcpSegment::from(pnet_TcpPacket::owned(self.packet().to_vec()).unwrap())
     }
 
+    /// Check the checksum against the addresses of the IP packet carrying the segment
+    ///
+    /// Returns `None` if the addresses aren't both IPv4 or both IPv6
+    pub fn checksum_status(&self, source: IpAddr, destination: IpAddr) -> Option<ChecksumStatus> {
+        let status = transport_status(
+            self.packet(),
+            IpNextHeaderProtocols::Tcp,
+            PseudoHeader::new(source, destination)?,
+            false,
+        );
+        Some(status.unwrap_or(ChecksumStatus::Invalid))
+    }
+
     fn is_answered_by(&self, other: &TcpSegment<'_>) -> bool {
         self.get_source() == other.get_destination()
             && self.get_destination() == other.get_source()
@@ -43,31 +91,22 @@ impl TcpSegment<'_> {
     }
 }
 
-/// Wrapper around an Arc<[TcpSegment]> for additional functionality
-#[derive(Debug)]
-pub struct TcpSegmentCollection<'a>(Arc<[TcpSegment<'a>]>);
-
-impl<'a> FromIterator<TcpSegment<'a>> for TcpSegmentCollection<'a> {
-    fn from_iter<I: IntoIterator<Item = TcpSegment<'a>>>(iter: I) -> Self {
-        TcpSegmentCollection(iter.into_iter().collect())
-    }
-}
-
-impl<'a> Deref for TcpSegmentCollection<'a> {
-    type Target = Arc<[TcpSegment<'a>]>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
+/// Collection of TcpSegment with the shared PacketCollection functionality
+pub type TcpSegmentCollection<'a> = PacketCollection<TcpSegment<'a>>;
 
 impl<'a> From<crate::Ipv4PacketCollection<'a>> for TcpSegmentCollection<'a> {
-    fn from(ipv4_packet_collection: crate::Ipv4PacketCollection) -> Self {
+    fn from(ipv4_packet_collection: crate::Ipv4PacketCollection<'a>) -> Self {
         ipv4_packet_collection
-            .iter()
-            .filter(|ipv4_packet| pnet_TcpPacket::new(ipv4_packet.payload()).is_some())
-            .map(|ipv4_packet| {
-                TcpSegment::from(pnet_TcpPacket::owned(ipv4_packet.payload().to_vec()).unwrap())
+            .par_iter()
+            .filter_map(|ipv4_packet| match ipv4_packet.source() {
+                // Borrow the segment from the same bytes as the packet when possible
+                Some(packet) => ipv4_to_tcp(packet).ok().flatten().map(TcpSegment::borrowed),
+                None => ipv4_to_tcp(ipv4_packet.packet())
+                    .ok()
+                    .flatten()
+                    .map(|segment| {
+                        TcpSegment::from(pnet_TcpPacket::owned(segment.to_vec()).unwrap())
+                    }),
             })
             .collect::<TcpSegmentCollection>()
     }
@@ -78,12 +117,7 @@ impl<'a> TcpSegmentCollection<'a> {
     ///
     /// Returns a new TcpSegmentCollection containing only the segments that have a TCP payload
     pub fn filter_no_payload(&'a self) -> TcpSegmentCollection<'a> {
-        TcpSegmentCollection(
-            self.iter()
-                .filter(|s| s.has_payload())
-                .map(|s| s.create_clone())
-                .collect::<Arc<[TcpSegment]>>(),
-        )
+        self.par_filter(|s| s.has_payload())
     }
 
     /// Couple the challenge / response pairs in a collection of TCP segments
@@ -93,22 +127,19 @@ impl<'a> TcpSegmentCollection<'a> {
         &'a mut self,
     ) -> (TcpChallengeResponseCollection<'a>, TcpSegmentCollection<'a>) {
         let mut matched = Vec::new();
-        let mut unmatched = self
-            .iter()
-            .map(|s| s.create_clone())
-            .collect::<Vec<TcpSegment<'a>>>();
+        let mut unmatched = self.iter().cloned().collect::<Vec<TcpSegment<'a>>>();
         let mut i = 0;
         while i < unmatched.len() {
-            let challenge = unmatched[i].create_clone();
+            let challenge = unmatched[i].clone();
             let mut j = 0;
             let mut found_match = false;
             while j < unmatched.len() - 1 {
                 j += 1;
-                let candidate = unmatched[j].create_clone();
+                let candidate = unmatched[j].clone();
                 if challenge.is_answered_by(&candidate) {
                     matched.push(TcpChallengeResponse::new(
-                        challenge.create_clone(),
-                        candidate.create_clone(),
+                        challenge.clone(),
+                        candidate.clone(),
                     ));
                     if j > i {
                         unmatched.remove(j);
@@ -126,14 +157,14 @@ impl<'a> TcpSegmentCollection<'a> {
             }
         }
         (
-            TcpChallengeResponseCollection(matched.into()),
-            TcpSegmentCollection(unmatched.into()),
+            TcpChallengeResponseCollection::from(matched),
+            TcpSegmentCollection::from(unmatched),
         )
     }
 }
 
 /// Container for TCP segments where the "challenge" was answered by the "response"
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TcpChallengeResponse<'a> {
     pub challenge: TcpSegment<'a>,
     pub response: TcpSegment<'a>,
@@ -148,23 +179,8 @@ impl<'a> TcpChallengeResponse<'a> {
     }
 }
 
-/// Wrapper around an Arc<[TcpChallengeResponse]> for additional functionality
-#[derive(Debug)]
-pub struct TcpChallengeResponseCollection<'a>(Arc<[TcpChallengeResponse<'a>]>);
-
-impl<'a> FromIterator<TcpChallengeResponse<'a>> for TcpChallengeResponseCollection<'a> {
-    fn from_iter<I: IntoIterator<Item = TcpChallengeResponse<'a>>>(iter: I) -> Self {
-        TcpChallengeResponseCollection(iter.into_iter().collect())
-    }
-}
-
-impl<'a> Deref for TcpChallengeResponseCollection<'a> {
-    type Target = Arc<[TcpChallengeResponse<'a>]>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
+/// Collection of TcpChallengeResponse with the shared PacketCollection functionality
+pub type TcpChallengeResponseCollection<'a> = PacketCollection<TcpChallengeResponse<'a>>;
 
 impl DerefMut for TcpChallengeResponseCollection<'_> {
     fn deref_mut(&mut self) -> &mut Self::Target {
@@ -0,0 +1,247 @@
+use crate::dissection::validate_ipv4;
+use crate::ethernet_frame::strip_frame_tags;
+use crate::ipv4_packet::ipv4_payload;
+use crate::{EthernetFrame, Ipv4Packet, Ipv6Packet};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket as pnet_EthernetPacket};
+use pnet::packet::gre::GrePacket as pnet_GrePacket;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet as pnet_Ipv4Packet;
+use pnet::packet::udp::UdpPacket as pnet_UdpPacket;
+use pnet::packet::vxlan::VxlanPacket as pnet_VxlanPacket;
+
+/// UDP destination port of VXLAN
+pub const VXLAN_PORT: u16 = 4789;
+/// UDP destination port of Geneve
+pub const GENEVE_PORT: u16 = 6081;
+/// Protocol type of ethernet frames carried by GRE and Geneve
+pub const TRANSPARENT_ETHERNET_BRIDGING: EtherType = EtherType(0x6558);
+
+/// Stop following tunnels past this depth so crafted packets can't loop forever
+pub(crate) const MAX_TUNNEL_DEPTH: usize = 8;
+
+/// Encapsulation carried inside an ipv4 packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tunnel {
+    /// Generic Routing Encapsulation, IP protocol 47
+    Gre {
+        protocol_type: EtherType,
+        key: Option<u32>,
+    },
+    /// Virtual eXtensible LAN over UDP port 4789
+    Vxlan { vni: u32 },
+    /// Generic Network Virtualization Encapsulation over UDP port 6081
+    Geneve { vni: u32, protocol_type: EtherType },
+    /// Ipv4 directly inside ipv4, IP protocol 4
+    IpInIp,
+}
+
+/// The header found inside a tunnel
+#[derive(Debug)]
+pub enum TunnelPayload<'a> {
+    Ethernet(EthernetFrame<'a>),
+    Ipv4(Ipv4Packet<'a>),
+    Ipv6(Ipv6Packet<'a>),
+    /// Tunnel payload of a protocol wiretap doesn't decode
+    Other(&'a [u8]),
+}
+
+/// Which packet the `results_as_*` methods decode when traffic is tunneled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Decapsulation {
+    /// Decode the packet as it was seen on the wire
+    #[default]
+    Outermost,
+    /// Follow every tunnel and decode the packet inside the last one
+    Innermost,
+}
+
+/// Raw bytes found inside a tunnel
+pub(crate) enum InnerBytes<'a> {
+    Ethernet(&'a [u8]),
+    Ipv4(&'a [u8]),
+    Ipv6(&'a [u8]),
+    Other(&'a [u8]),
+}
+
+impl<'a> From<InnerBytes<'a>> for TunnelPayload<'a> {
+    fn from(inner: InnerBytes<'a>) -> Self {
+        match inner {
+            InnerBytes::Ethernet(bytes) => EthernetFrame::new(bytes)
+                .map(TunnelPayload::Ethernet)
+                .unwrap_or(TunnelPayload::Other(bytes)),
+            InnerBytes::Ipv4(bytes) => Ipv4Packet::new(bytes)
+                .map(TunnelPayload::Ipv4)
+                .unwrap_or(TunnelPayload::Other(bytes)),
+            InnerBytes::Ipv6(bytes) => Ipv6Packet::new(bytes)
+                .map(TunnelPayload::Ipv6)
+                .unwrap_or(TunnelPayload::Other(bytes)),
+            InnerBytes::Other(bytes) => TunnelPayload::Other(bytes),
+        }
+    }
+}
+
+/// Find the tunnel carried by a raw ipv4 packet
+pub(crate) fn decapsulate(ipv4: &[u8]) -> Option<(Tunnel, InnerBytes<'_>)> {
+    validate_ipv4(ipv4).ok()?;
+    let ipv4_packet = pnet_Ipv4Packet::new(ipv4).unwrap();
+    // Only the first fragment starts with the tunnel header
+    if ipv4_packet.get_fragment_offset() != 0 {
+        return None;
+    }
+    let payload = ipv4_payload(ipv4);
+    match ipv4_packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Ipv4 => Some((Tunnel::IpInIp, InnerBytes::Ipv4(payload))),
+        IpNextHeaderProtocols::Gre => decapsulate_gre(payload),
+        IpNextHeaderProtocols::Udp => decapsulate_udp(payload),
+        _ => None,
+    }
+}
+
+fn decapsulate_gre(gre: &[u8]) -> Option<(Tunnel, InnerBytes<'_>)> {
+    let gre_packet = pnet_GrePacket::new(gre)?;
+    // Source routed (deprecated) and enhanced (PPTP) GRE aren't used for tunneling ipv4
+    if gre_packet.get_version() != 0 || gre_packet.get_routing_present() == 1 {
+        return None;
+    }
+    let mut header_length = pnet_GrePacket::minimum_packet_size();
+    if gre_packet.get_checksum_present() == 1 {
+        header_length += 4;
+    }
+    let key = match gre_packet.get_key_present() {
+        1 => {
+            let key = gre.get(header_length..header_length + 4)?;
+            header_length += 4;
+            Some(u32::from_be_bytes([key[0], key[1], key[2], key[3]]))
+        }
+        _ => None,
+    };
+    if gre_packet.get_sequence_present() == 1 {
+        header_length += 4;
+    }
+    let inner = gre.get(header_length..)?;
+    let protocol_type = EtherType(gre_packet.get_protocol_type());
+    Some((
+        Tunnel::Gre { protocol_type, key },
+        inner_bytes(protocol_type, inner),
+    ))
+}
+
+pub(crate) fn decapsulate_udp(udp: &[u8]) -> Option<(Tunnel, InnerBytes<'_>)> {
+    let udp_packet = pnet_UdpPacket::new(udp)?;
+    let header_length = pnet_UdpPacket::minimum_packet_size();
+    let payload =
+        &udp[header_length..usize::from(udp_packet.get_length()).clamp(header_length, udp.len())];
+    match udp_packet.get_destination() {
+        VXLAN_PORT => {
+            let vxlan_packet = pnet_VxlanPacket::new(payload)?;
+            // The I flag marks a valid VNI
+            if vxlan_packet.get_flags() & 0x08 == 0 {
+                return None;
+            }
+            let inner = &payload[pnet_VxlanPacket::minimum_packet_size()..];
+            Some((
+                Tunnel::Vxlan {
+                    vni: vxlan_packet.get_vni(),
+                },
+                InnerBytes::Ethernet(inner),
+            ))
+        }
+        GENEVE_PORT => {
+            // Version (2 bits) must be 0, options length (6 bits) is in 4 byte words
+            if payload.len() < 8 || payload[0] >> 6 != 0 {
+                return None;
+            }
+            let header_length = 8 + usize::from(payload[0] & 0x3f) * 4;
+            let protocol_type = EtherType(u16::from_be_bytes([payload[2], payload[3]]));
+            let vni = u32::from_be_bytes([0, payload[4], payload[5], payload[6]]);
+            let inner = payload.get(header_length..)?;
+            Some((
+                Tunnel::Geneve { vni, protocol_type },
+                inner_bytes(protocol_type, inner),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn inner_bytes(protocol_type: EtherType, inner: &[u8]) -> InnerBytes<'_> {
+    match protocol_type {
+        EtherTypes::Ipv4 => InnerBytes::Ipv4(inner),
+        EtherTypes::Ipv6 => InnerBytes::Ipv6(inner),
+        TRANSPARENT_ETHERNET_BRIDGING => InnerBytes::Ethernet(inner),
+        _ => InnerBytes::Other(inner),
+    }
+}
+
+/// Get the ipv4 packet inside a tunnel, looking through an inner ethernet frame if needed
+fn inner_ipv4(inner: InnerBytes<'_>) -> Option<&[u8]> {
+    let ipv4 = match inner {
+        InnerBytes::Ipv4(ipv4) => ipv4,
+        InnerBytes::Ethernet(frame) => {
+            pnet_EthernetPacket::new(frame)?;
+            let stripped = strip_frame_tags(frame);
+            if stripped.ethertype != EtherTypes::Ipv4 {
+                return None;
+            }
+            stripped.payload
+        }
+        InnerBytes::Ipv6(_) | InnerBytes::Other(_) => return None,
+    };
+    validate_ipv4(ipv4).ok().map(|_| ipv4)
+}
+
+/// Get every tunnel nested in a raw ipv4 packet, outermost first
+pub(crate) fn nested_tunnels(ipv4: &[u8]) -> Vec<Tunnel> {
+    let mut tunnels = vec![];
+    let mut current = ipv4;
+    while tunnels.len() < MAX_TUNNEL_DEPTH {
+        let Some((tunnel, inner)) = decapsulate(current) else {
+            break;
+        };
+        tunnels.push(tunnel);
+        match inner_ipv4(inner) {
+            Some(ipv4) => current = ipv4,
+            None => break,
+        }
+    }
+    tunnels
+}
+
+/// Follow the tunnels of a raw ipv4 packet down to the innermost ipv4 packet
+pub(crate) fn innermost_ipv4(ipv4: &[u8]) -> &[u8] {
+    let mut current = ipv4;
+    for _ in 0..MAX_TUNNEL_DEPTH {
+        match decapsulate(current).and_then(|(_, inner)| inner_ipv4(inner)) {
+            Some(ipv4) => current = ipv4,
+            None => break,
+        }
+    }
+    current
+}
+
+/// Follow the tunnels of a raw ethernet frame down to the innermost ethernet frame
+pub(crate) fn innermost_ethernet(frame: &[u8]) -> &[u8] {
+    let mut current = frame;
+    let mut depth = 0;
+    'frames: while depth < MAX_TUNNEL_DEPTH {
+        let stripped = strip_frame_tags(current);
+        if stripped.ethertype != EtherTypes::Ipv4 {
+            break;
+        }
+        let mut ipv4 = stripped.payload;
+        while depth < MAX_TUNNEL_DEPTH {
+            depth += 1;
+            match decapsulate(ipv4) {
+                Some((_, InnerBytes::Ethernet(inner)))
+                    if pnet_EthernetPacket::new(inner).is_some() =>
+                {
+                    current = inner;
+                    continue 'frames;
+                }
+                Some((_, InnerBytes::Ipv4(inner))) => ipv4 = inner,
+                _ => break 'frames,
+            }
+        }
+    }
+    current
+}
@@ -0,0 +1,102 @@
+use crate::checksum::{transport_status, ChecksumStatus, PseudoHeader};
+use crate::dissection::{validate_udp, Malformation};
+use crate::PacketCollection;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::udp::UdpPacket as pnet_UdpPacket;
+use pnet::packet::Packet;
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::Deref;
+
+/// Wrapper around pnet's UdpPacket for adding additional funcitonality
+///
+/// A wrapper created from a byte slice remembers it, so cloning the wrapper doesn't copy the packet
+pub struct UdpDatagram<'a>(pnet_UdpPacket<'a>, Option<&'a [u8]>);
+
+impl<'a> From<pnet_UdpPacket<'a>> for UdpDatagram<'a> {
+    fn from(udp_packet: pnet_UdpPacket<'a>) -> Self {
+        UdpDatagram(udp_packet, None)
+    }
+}
+
+impl<'a> Deref for UdpDatagram<'a> {
+    type Target = pnet_UdpPacket<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Clone for UdpDatagram<'_> {
+    fn clone(&self) -> Self {
+        match self.1 {
+            Some(packet) => UdpDatagram::borrowed(packet),
+            None => self.create_clone(),
+        }
+    }
+}
+
+impl fmt::Debug for UdpDatagram<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("UdpDatagram").field(&self.0).finish()
+    }
+}
+
+impl<'a> UdpDatagram<'a> {
+    /// Wrap bytes already known to hold the header, without copying them
+    pub(crate) fn borrowed(packet: &'a [u8]) -> UdpDatagram<'a> {
+        UdpDatagram(pnet_UdpPacket::new(packet).unwrap(), Some(packet))
+    }
+}
+
+impl UdpDatagram<'_> {
+    pub fn new<'a>(packet: &'a [u8]) -> Option<UdpDatagram<'a>> {
+        UdpDatagram::try_new(packet).ok()
+    }
+
+    /// Create a UdpDatagram after checking the length field of the header
+    pub fn try_new(packet: &[u8]) -> Result<UdpDatagram<'_>, Malformation> {
+        validate_udp(packet)?;
+        Ok(UdpDatagram::borrowed(packet))
+    }
+
+    /// Return true if the UDP datagram has a payload
+    pub fn has_payload(&self) -> bool {
+        !&self.payload().is_empty()
+    }
+
+    pub fn create_clone<'a>(&self) -> UdpDatagram<'a> {
+        UdpDatagram::from(pnet_UdpPacket::owned(self.packet().to_vec()).unwrap())
+    }
+
+    /// Check the checksum against the addresses of the IP packet carrying the datagram
+    ///
+    /// Returns `None` if the addresses aren't both IPv4 or both IPv6
+    pub fn checksum_status(&self, source: IpAddr, destination: IpAddr) -> Option<ChecksumStatus> {
+        let status = transport_status(
+            self.packet(),
+            IpNextHeaderProtocols::Udp,
+            PseudoHeader::new(source, destination)?,
+            false,
+        );
+        Some(status.unwrap_or(ChecksumStatus::Invalid))
+    }
+}
+
+/// Get the payload of a well formed raw udp datagram, bounded by its length field
+pub(crate) fn udp_payload(datagram: &[u8]) -> &[u8] {
+    let length = usize::from(u16::from_be_bytes([datagram[4], datagram[5]]));
+    &datagram[pnet_UdpPacket::minimum_packet_size()..length]
+}
+
+/// Collection of UdpDatagram with the shared PacketCollection functionality
+pub type UdpDatagramCollection<'a> = PacketCollection<UdpDatagram<'a>>;
+
+impl<'a> UdpDatagramCollection<'a> {
+    /// Get a collection of UdpDatagram with UDP payloads
+    ///
+    /// Returns a new UdpDatagramCollection containing only the datagrams that have a UDP payload
+    pub fn filter_no_payload(&'a self) -> UdpDatagramCollection<'a> {
+        self.par_filter(|d| d.has_payload())
+    }
+}